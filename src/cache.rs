@@ -0,0 +1,132 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::{git::Commit, provider::Provider, Options, Release};
+
+/// Generated changelogs stored on disk, so re-running the same range doesn't bill the
+/// API again.
+pub struct Cache {
+    dir: PathBuf,
+}
+
+/// A cached changelog.
+#[derive(Serialize, Deserialize, Debug)]
+struct Entry {
+    model: String,
+    heading: String,
+    text: String,
+}
+
+impl Cache {
+    /// The cache in `~/.cache/aichangelog` (or the platform's equivalent).
+    pub fn new() -> anyhow::Result<Self> {
+        let Some(dir) = dirs::cache_dir() else {
+            anyhow::bail!("Could not find a cache directory");
+        };
+        Ok(Self::at(dir.join("aichangelog")))
+    }
+
+    pub fn at(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The changelog stored under `key`, if any.
+    pub fn get(&self, key: &str) -> Option<String> {
+        let content = fs::read_to_string(self.path(key)).ok()?;
+        let entry: Entry = serde_json::from_str(&content).ok()?;
+        Some(entry.text)
+    }
+
+    pub fn put(&self, key: &str, model: &str, release: &Release, text: &str) -> anyhow::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let entry = Entry {
+            model: model.to_string(),
+            heading: release.heading.clone(),
+            text: text.to_string(),
+        };
+        // Written to a temporary file first so a concurrent run never reads half an entry.
+        let tmp = self.dir.join(format!("{key}.json.tmp"));
+        fs::write(&tmp, serde_json::to_string(&entry)?)?;
+        fs::rename(tmp, self.path(key))?;
+        Ok(())
+    }
+
+    /// Removes every cached changelog and returns how many there were.
+    pub fn clear(&self) -> anyhow::Result<usize> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        let mut removed = 0;
+        for entry in entries {
+            let path = entry?.path();
+            if path
+                .extension()
+                .is_some_and(|ext| ext == "json" || ext == "tmp")
+            {
+                fs::remove_file(path)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{key}.json"))
+    }
+}
+
+/// Cache key of the changelog of `commits`.
+///
+/// Covers the model and the server it runs on (`base_url`), the sampling settings, the
+/// commits and everything that shapes the prompt: the template, the options that change
+/// how commits are rendered, the format, the heading, what enrichment found out about
+/// the commits, the context size, which decides whether the log is summarized, and the
+/// version of aichangelog, whose built-in prompts change between releases. Keys are
+/// SHA-256 digests, so they stay the same across Rust versions.
+pub fn key(
+    provider: &dyn Provider,
+    base_url: &str,
+    options: &Options,
+    release: &Release,
+    commits: &[Commit],
+) -> String {
+    let sections: Vec<_> = options
+        .sections
+        .iter()
+        .map(|section| json!([section.title, section.types, section.labels]))
+        .collect();
+    let commits: Vec<_> = commits
+        .iter()
+        .map(|commit| json!([commit.hash, commit.describe_references(false)]))
+        .collect();
+    let input = json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "model": provider.model(),
+        "context_size": provider.context_size(),
+        "base_url": base_url.trim_end_matches('/'),
+        "prompt": options.prompt,
+        "short": options.short,
+        "conventional": options.conventional,
+        "sections": sections,
+        "audience": options.audience,
+        "project_name": options.project_name,
+        "format": options.format.to_string(),
+        "heading": release.heading,
+        "commits": commits,
+        "temp": options.temp.to_bits(),
+        "freq": options.freq.to_bits(),
+    });
+    format!("{:x}", Sha256::digest(input.to_string()))
+}
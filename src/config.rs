@@ -0,0 +1,154 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::{
+    conventional::Section,
+    filter::FilterConfig,
+    forge::ForgeConfig,
+    format::Format,
+    git,
+    openai::{Model, Pricing},
+    workspace::PackageConfig,
+};
+
+/// Name of the repository-local config file, looked up at the root of the work tree.
+pub const LOCAL_FILE: &str = ".aichangelog.toml";
+
+/// Settings read from `~/.config/aichangelog/config.toml` and the repository's
+/// `.aichangelog.toml`. Every field is optional; flags given on the command line win.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub model: Option<Model>,
+    pub base_url: Option<String>,
+    pub context_size: Option<usize>,
+    pub retries: Option<u32>,
+    pub temp: Option<f64>,
+    pub freq: Option<f64>,
+    pub short: Option<bool>,
+    pub conventional: Option<bool>,
+    /// Head unreleased changes with the next semantic version.
+    pub bump: Option<bool>,
+    pub tag_pattern: Option<String>,
+    pub output: Option<PathBuf>,
+    /// Root of the work tree, which `output` is relative to.
+    #[serde(skip)]
+    pub output_dir: Option<PathBuf>,
+    pub format: Option<Format>,
+    /// How often to ask the model to fix JSON that doesn't match the schema.
+    pub repairs: Option<u32>,
+    pub audience: Option<String>,
+    pub project_name: Option<String>,
+    /// Prompt template replacing the built-in system message.
+    pub prompt: Option<String>,
+    /// File to read the prompt template from, relative to the config file.
+    pub prompt_file: Option<PathBuf>,
+    /// Sections commits are grouped into with `--conventional`, in order.
+    pub sections: Option<Vec<Section>>,
+    /// Prices per model, overriding the built-in ones.
+    pub pricing: Option<HashMap<String, Pricing>>,
+    /// Which commits to leave out of the changelog.
+    pub filters: Option<FilterConfig>,
+    /// Generate one changelog per package.
+    pub workspace: Option<bool>,
+    /// Packages besides the members of a Cargo workspace.
+    pub packages: Option<Vec<PackageConfig>>,
+    /// Look up referenced issues and pull requests on the forge.
+    pub enrich: Option<bool>,
+    /// Where the repository is hosted, for `enrich`.
+    pub forge: Option<ForgeConfig>,
+}
+
+impl Config {
+    /// Loads the global config, then the one of `repo` on top of it.
+    pub fn load(repo: &Path) -> anyhow::Result<Self> {
+        let global = match global_path() {
+            Some(path) => Self::read(&path)?,
+            None => None,
+        };
+        let root = git::toplevel(repo).ok();
+        let local = Self::read(&root.clone().unwrap_or_default().join(LOCAL_FILE))?;
+        let mut config = global.unwrap_or_default().merge(local.unwrap_or_default());
+        // Relative to the repository, even when set globally, so every repository keeps
+        // its own changelog.
+        if config.output.is_some() {
+            config.output_dir = root;
+        }
+        Ok(config)
+    }
+
+    fn read(path: &Path) -> anyhow::Result<Option<Self>> {
+        match fs::read_to_string(path) {
+            Ok(content) => {
+                let mut config: Self = toml::from_str(&content)
+                    .map_err(|e| anyhow::anyhow!("Invalid config {}: {}", path.display(), e))?;
+                if let (Some(file), Some(dir)) = (&config.prompt_file, path.parent()) {
+                    config.prompt_file = Some(dir.join(file));
+                }
+                Ok(Some(config))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(anyhow::anyhow!("Could not read {}: {}", path.display(), e)),
+        }
+    }
+
+    /// Layers `other` on top of `self`.
+    fn merge(self, other: Self) -> Self {
+        Self {
+            model: other.model.or(self.model),
+            base_url: other.base_url.or(self.base_url),
+            context_size: other.context_size.or(self.context_size),
+            retries: other.retries.or(self.retries),
+            temp: other.temp.or(self.temp),
+            freq: other.freq.or(self.freq),
+            short: other.short.or(self.short),
+            conventional: other.conventional.or(self.conventional),
+            bump: other.bump.or(self.bump),
+            tag_pattern: other.tag_pattern.or(self.tag_pattern),
+            output_dir: None,
+            output: other.output.or(self.output),
+            format: other.format.or(self.format),
+            repairs: other.repairs.or(self.repairs),
+            audience: other.audience.or(self.audience),
+            project_name: other.project_name.or(self.project_name),
+            // A prompt in `other` must not lose against a prompt file inherited from `self`.
+            prompt_file: if other.prompt.is_some() {
+                other.prompt_file
+            } else {
+                other.prompt_file.or(self.prompt_file)
+            },
+            prompt: other.prompt.or(self.prompt),
+            sections: other.sections.or(self.sections),
+            pricing: match (self.pricing, other.pricing) {
+                (Some(mut pricing), Some(other)) => {
+                    pricing.extend(other);
+                    Some(pricing)
+                }
+                (pricing, other) => other.or(pricing),
+            },
+            workspace: other.workspace.or(self.workspace),
+            packages: other.packages.or(self.packages),
+            enrich: other.enrich.or(self.enrich),
+            forge: match (self.forge, other.forge) {
+                (Some(forge), Some(other)) => Some(forge.merge(other)),
+                (forge, other) => other.or(forge),
+            },
+            filters: match (self.filters, other.filters) {
+                (Some(filters), Some(other)) => Some(filters.merge(other)),
+                (filters, other) => other.or(filters),
+            },
+        }
+    }
+}
+
+fn global_path() -> Option<PathBuf> {
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| dirs::home_dir().map(|home| home.join(".config")))?;
+    Some(config_home.join("aichangelog").join("config.toml"))
+}
@@ -0,0 +1,167 @@
+use std::fmt::Write;
+
+use serde::Deserialize;
+
+use crate::git::Commit;
+
+/// A commit message following the Conventional Commits specification.
+#[derive(Debug)]
+pub struct ConventionalCommit {
+    pub kind: String,
+    pub scope: Option<String>,
+    pub breaking: bool,
+    pub description: String,
+    pub body: String,
+}
+
+impl ConventionalCommit {
+    /// Parses `type(scope)!: description`, followed by an optional body and footers.
+    pub fn parse(message: &str) -> Option<Self> {
+        let (header, body) = message.split_once('\n').unwrap_or((message, ""));
+        let (prefix, description) = header.split_once(": ")?;
+
+        let (prefix, bang) = match prefix.strip_suffix('!') {
+            Some(prefix) => (prefix, true),
+            None => (prefix, false),
+        };
+        let (kind, scope) = match prefix.split_once('(') {
+            Some((kind, scope)) => (kind, Some(scope.strip_suffix(')')?)),
+            None => (prefix, None),
+        };
+        if kind.is_empty() || !kind.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return None;
+        }
+        if scope.is_some_and(|s| s.is_empty() || s.contains(['(', ')'])) {
+            return None;
+        }
+
+        let body = body.trim();
+        let breaking = bang
+            || body.lines().any(|line| {
+                line.starts_with("BREAKING CHANGE:") || line.starts_with("BREAKING-CHANGE:")
+            });
+
+        Some(Self {
+            kind: kind.to_lowercase(),
+            scope: scope.map(String::from),
+            breaking,
+            description: description.trim().to_string(),
+            body: body.to_string(),
+        })
+    }
+
+    /// Index into `sections` this commit belongs to, `None` for "Other".
+    ///
+    /// Breaking changes are never looked up; they always go to "Breaking Changes".
+    fn section(&self, sections: &[Section]) -> Option<usize> {
+        sections
+            .iter()
+            .position(|section| section.types.contains(&self.kind))
+    }
+}
+
+/// Index into `sections` of the first section claiming one of `commit`'s labels.
+fn label_section(commit: &Commit, sections: &[Section]) -> Option<usize> {
+    sections.iter().position(|section| {
+        commit
+            .labels()
+            .any(|label| section.labels.iter().any(|l| l.eq_ignore_ascii_case(label)))
+    })
+}
+
+/// A changelog section and the Conventional Commit types that belong in it.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Section {
+    pub title: String,
+    #[serde(default)]
+    pub types: Vec<String>,
+    /// Labels of referenced issues and pull requests that put a commit in this section
+    /// when its type doesn't.
+    #[serde(default)]
+    pub labels: Vec<String>,
+}
+
+pub const BREAKING_TITLE: &str = "Breaking Changes";
+pub const OTHER_TITLE: &str = "Other";
+
+pub fn default_sections() -> Vec<Section> {
+    [
+        ("Features", "feat"),
+        ("Fixes", "fix"),
+        ("Performance", "perf"),
+    ]
+    .into_iter()
+    .map(|(title, kind)| Section {
+        title: String::from(title),
+        types: vec![String::from(kind)],
+        labels: Vec::new(),
+    })
+    .collect()
+}
+
+/// Renders `commits` grouped into sections, ready to be sent to the model.
+///
+/// Breaking changes come first and everything that matches none of `sections` by type
+/// or by the labels of its references, including commits that don't follow Conventional
+/// Commits, ends up in "Other".
+pub fn group(commits: &[Commit], sections: &[Section], short: bool) -> String {
+    let titles: Vec<&str> = std::iter::once(BREAKING_TITLE)
+        .chain(sections.iter().map(|s| s.title.as_str()))
+        .chain(std::iter::once(OTHER_TITLE))
+        .collect();
+    let mut buckets = vec![String::new(); titles.len()];
+
+    for commit in commits {
+        let (bucket, mut entry) = match ConventionalCommit::parse(&commit.message()) {
+            Some(cc) => {
+                let mut entry = format!("- {} ", commit.short_hash);
+                if let Some(scope) = &cc.scope {
+                    let _ = write!(entry, "({scope}) ");
+                }
+                entry.push_str(&cc.description);
+                if !short && !cc.body.is_empty() {
+                    entry.push('\n');
+                    entry.push_str(&indent(&cc.body));
+                }
+                let bucket = if cc.breaking {
+                    0
+                } else {
+                    cc.section(sections)
+                        .or_else(|| label_section(commit, sections))
+                        .map_or(titles.len() - 1, |i| i + 1)
+                };
+                (bucket, entry)
+            }
+            None => {
+                let mut entry = format!("- {} {}", commit.short_hash, commit.subject);
+                if !short && !commit.body.is_empty() {
+                    entry.push('\n');
+                    entry.push_str(&indent(&commit.body));
+                }
+                let bucket = label_section(commit, sections).map_or(titles.len() - 1, |i| i + 1);
+                (bucket, entry)
+            }
+        };
+        entry.push('\n');
+        entry.push_str(&commit.describe_references(short));
+        buckets[bucket].push_str(&entry);
+    }
+
+    let mut out = String::new();
+    for (title, entries) in titles.iter().zip(buckets) {
+        if !entries.is_empty() {
+            let _ = write!(out, "## {}\n\n{}\n", title, entries);
+        }
+    }
+    out
+}
+
+fn indent(text: &str) -> String {
+    text.lines()
+        .map(|line| format!("  {line}"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+pub const SYSTEM_MSG: &str = r#"You are now an AI that takes Git commit messages that are already grouped into sections as input and generates a changelog in the style of update notes using Markdown formatting. Keep the given sections in their order and never move an entry into a different section; only rewrite the entries into clear, user-facing prose. Leave out sections that end up empty."#;
@@ -0,0 +1,82 @@
+use std::path::Path;
+
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::git::{self, Commit};
+
+/// Decides which commits of a range end up in the prompt.
+///
+/// Filtering happens before anything is counted or summarized, so noisy ranges shrink
+/// to what is worth describing.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    /// Only keep commits touching these paths, relative to the current directory.
+    pub paths: Vec<String>,
+    pub no_merges: bool,
+    /// Drop commits whose author, as `Name <email>`, matches any of these.
+    pub exclude_authors: Vec<Regex>,
+    /// Drop commits whose message matches any of these.
+    pub exclude_messages: Vec<Regex>,
+}
+
+impl Filter {
+    /// Builds a filter from the patterns in `config`.
+    pub fn new(config: &FilterConfig) -> anyhow::Result<Self> {
+        Ok(Self {
+            paths: config.paths.clone(),
+            no_merges: config.no_merges,
+            exclude_authors: compile(&config.exclude_authors)?,
+            exclude_messages: compile(&config.exclude_messages)?,
+        })
+    }
+
+    /// Reads the commits in `range` of `repo` that pass the filter, newest first.
+    pub fn commits(&self, repo: &Path, range: Option<&str>) -> anyhow::Result<Vec<Commit>> {
+        let mut commits = git::commits(repo, range, &self.paths, self.no_merges)?;
+        commits.retain(|commit| self.keeps(commit));
+        Ok(commits)
+    }
+
+    /// Whether `commit` passes the author and message patterns.
+    pub fn keeps(&self, commit: &Commit) -> bool {
+        let author = format!("{} <{}>", commit.author, commit.email);
+        if self.exclude_authors.iter().any(|re| re.is_match(&author)) {
+            return false;
+        }
+        let message = commit.message();
+        !self.exclude_messages.iter().any(|re| re.is_match(&message))
+    }
+}
+
+/// The `[filters]` table of the config file, also filled from the command line.
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct FilterConfig {
+    pub paths: Vec<String>,
+    pub no_merges: bool,
+    pub exclude_authors: Vec<String>,
+    pub exclude_messages: Vec<String>,
+}
+
+impl FilterConfig {
+    /// Layers `other` on top of `self`: its paths win, the exclusions add up.
+    pub fn merge(mut self, other: Self) -> Self {
+        if !other.paths.is_empty() {
+            self.paths = other.paths;
+        }
+        self.no_merges |= other.no_merges;
+        self.exclude_authors.extend(other.exclude_authors);
+        self.exclude_messages.extend(other.exclude_messages);
+        self
+    }
+}
+
+fn compile(patterns: &[String]) -> anyhow::Result<Vec<Regex>> {
+    patterns
+        .iter()
+        .map(|pattern| {
+            Regex::new(pattern).map_err(|e| anyhow::anyhow!("Invalid pattern {pattern:?}: {e}"))
+        })
+        .collect()
+}
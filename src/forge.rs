@@ -0,0 +1,467 @@
+use std::{fmt, path::Path, time::Duration};
+
+use futures::stream::{self, StreamExt};
+use reqwest::{Method, StatusCode};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::git::{self, Commit};
+
+/// How many references are resolved at the same time.
+const CONCURRENCY: usize = 8;
+
+/// Descriptions are cut to this many characters to keep the prompt small.
+const MAX_DESCRIPTION: usize = 300;
+
+/// The kind of server hosting the repository.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ForgeKind {
+    GitHub,
+    GitLab,
+    Gitea,
+}
+
+impl ForgeKind {
+    /// Environment variable the API token is read from.
+    pub const fn token_var(self) -> &'static str {
+        match self {
+            Self::GitHub => "GITHUB_TOKEN",
+            Self::GitLab => "GITLAB_TOKEN",
+            Self::Gitea => "GITEA_TOKEN",
+        }
+    }
+}
+
+/// The `[forge]` table of the config file. Whatever is left out is derived from the
+/// `origin` remote.
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ForgeConfig {
+    pub kind: Option<ForgeKind>,
+    /// Base URL of the REST API, e.g. `https://api.github.com`.
+    pub api_url: Option<String>,
+    /// Path of the repository on the forge, e.g. `owner/name`.
+    pub repo: Option<String>,
+}
+
+impl ForgeConfig {
+    /// Layers `other` on top of `self`.
+    pub fn merge(self, other: Self) -> Self {
+        Self {
+            kind: other.kind.or(self.kind),
+            api_url: other.api_url.or(self.api_url),
+            repo: other.repo.or(self.repo),
+        }
+    }
+}
+
+/// A reference to an issue or a pull/merge request in a commit message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Reference {
+    /// `#123`; on GitHub and Gitea this may also be a pull request.
+    Issue(u64),
+    /// `!123`, GitLab's notation for merge requests.
+    MergeRequest(u64),
+}
+
+impl Reference {
+    pub const fn number(self) -> u64 {
+        match self {
+            Self::Issue(number) | Self::MergeRequest(number) => number,
+        }
+    }
+}
+
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Issue(n) => write!(f, "#{n}"),
+            Self::MergeRequest(n) => write!(f, "!{n}"),
+        }
+    }
+}
+
+/// Finds the `#123` and `!123` references in `message`, in order of appearance.
+pub fn references(message: &str) -> Vec<Reference> {
+    let mut refs = Vec::new();
+    let mut prev = None;
+    for (i, c) in message.char_indices() {
+        let boundary = prev.is_none_or(|p: char| !p.is_alphanumeric() && !"&/_-".contains(p));
+        prev = Some(c);
+        if !boundary || (c != '#' && c != '!') {
+            continue;
+        }
+        let digits: String = message[i + 1..]
+            .chars()
+            .take_while(char::is_ascii_digit)
+            .collect();
+        let after = message[i + 1 + digits.len()..].chars().next();
+        if digits.is_empty() || after.is_some_and(char::is_alphanumeric) {
+            continue;
+        }
+        let Ok(number) = digits.parse() else { continue };
+        let reference = match c {
+            '#' => Reference::Issue(number),
+            _ => Reference::MergeRequest(number),
+        };
+        if !refs.contains(&reference) {
+            refs.push(reference);
+        }
+    }
+    refs
+}
+
+/// An issue or pull/merge request as returned by the forge.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Issue {
+    pub reference: Reference,
+    pub title: String,
+    pub labels: Vec<String>,
+    pub description: String,
+    pub pull_request: bool,
+}
+
+impl Issue {
+    fn from_json(reference: Reference, value: &Value) -> Self {
+        let text = |key: &str| value[key].as_str().unwrap_or_default().trim().to_string();
+        let labels = value["labels"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|label| label.as_str().or_else(|| label["name"].as_str()))
+            .map(String::from)
+            .collect();
+        let description = match value["body"].as_str() {
+            Some(_) => text("body"),
+            None => text("description"),
+        };
+        Self {
+            reference,
+            title: text("title"),
+            labels,
+            description,
+            pull_request: matches!(reference, Reference::MergeRequest(_))
+                || value.get("pull_request").is_some_and(|pr| !pr.is_null()),
+        }
+    }
+
+    /// First paragraph of the description, shortened to [`MAX_DESCRIPTION`] characters.
+    pub fn summary(&self) -> String {
+        let paragraph = self
+            .description
+            .split("\n\n")
+            .next()
+            .unwrap_or_default()
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ");
+        match paragraph.char_indices().nth(MAX_DESCRIPTION) {
+            Some((end, _)) => format!("{}...", &paragraph[..end]),
+            None => paragraph,
+        }
+    }
+}
+
+/// Client for the REST API of a GitHub, GitLab or Gitea server.
+pub struct Forge {
+    client: reqwest::Client,
+    kind: ForgeKind,
+    api_url: String,
+    repo: String,
+    token: Option<String>,
+}
+
+impl Forge {
+    pub fn new(kind: ForgeKind, api_url: &str, repo: &str, token: Option<String>) -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(30))
+                .user_agent(concat!("aichangelog/", env!("CARGO_PKG_VERSION")))
+                .build()
+                .unwrap_or_default(),
+            kind,
+            api_url: api_url.trim_end_matches('/').to_string(),
+            repo: repo.trim_matches('/').to_string(),
+            token,
+        }
+    }
+
+    /// Works out the forge from `config` and the `origin` remote of `repo`, reading the
+    /// token from the environment variable of its kind.
+    pub fn detect(repo: &Path, config: &ForgeConfig) -> anyhow::Result<Self> {
+        let remote = match (&config.kind, &config.api_url, &config.repo) {
+            (Some(_), Some(_), Some(_)) => None,
+            _ => Some(git::remote_url(repo, "origin")?),
+        };
+        let (host, path) = match remote.as_deref().and_then(parse_remote) {
+            Some((host, path)) => (Some(host), Some(path)),
+            None => (None, None),
+        };
+        let kind = match (config.kind, host.as_deref()) {
+            (Some(kind), _) => kind,
+            (None, Some("github.com")) => ForgeKind::GitHub,
+            (None, Some(host)) if host.contains("gitlab") => ForgeKind::GitLab,
+            _ => anyhow::bail!("Could not tell the kind of forge; set kind in [forge]"),
+        };
+        let api_url = match (&config.api_url, host.as_deref()) {
+            (Some(url), _) => url.clone(),
+            (None, Some("github.com")) => String::from("https://api.github.com"),
+            (None, Some(host)) => match kind {
+                ForgeKind::GitHub => format!("https://{host}/api/v3"),
+                ForgeKind::GitLab => format!("https://{host}/api/v4"),
+                ForgeKind::Gitea => format!("https://{host}/api/v1"),
+            },
+            (None, None) => {
+                anyhow::bail!("Could not tell the forge's API URL; set api_url in [forge]")
+            }
+        };
+        let Some(repo) = config.repo.clone().or(path) else {
+            anyhow::bail!("Could not tell the repository's path; set repo in [forge]");
+        };
+        let token = std::env::var(kind.token_var()).ok();
+        Ok(Self::new(kind, &api_url, &repo, token))
+    }
+
+    pub const fn kind(&self) -> ForgeKind {
+        self.kind
+    }
+
+    /// `path` below the repository's API URL.
+    pub fn url(&self, path: &str) -> String {
+        match self.kind {
+            ForgeKind::GitLab => format!(
+                "{}/projects/{}{}",
+                self.api_url,
+                self.repo.replace('/', "%2F"),
+                path
+            ),
+            ForgeKind::GitHub | ForgeKind::Gitea => {
+                format!("{}/repos/{}{}", self.api_url, self.repo, path)
+            }
+        }
+    }
+
+    /// Starts a request to `url`, authenticated if there is a token.
+    pub fn request(&self, method: reqwest::Method, url: &str) -> reqwest::RequestBuilder {
+        let mut builder = self.client.request(method, url);
+        if self.kind == ForgeKind::GitHub {
+            builder = builder.header("Accept", "application/vnd.github+json");
+        }
+        match (&self.token, self.kind) {
+            (Some(token), ForgeKind::GitHub) => builder.bearer_auth(token),
+            (Some(token), ForgeKind::GitLab) => builder.header("PRIVATE-TOKEN", token),
+            (Some(token), ForgeKind::Gitea) => {
+                builder.header("Authorization", format!("token {token}"))
+            }
+            (None, _) => builder,
+        }
+    }
+
+    /// Looks up `reference`; `None` if the forge doesn't know it.
+    pub async fn issue(&self, reference: Reference) -> anyhow::Result<Option<Issue>> {
+        let path = match (self.kind, reference) {
+            (ForgeKind::GitLab, Reference::MergeRequest(n)) => format!("/merge_requests/{n}"),
+            (_, reference) => format!("/issues/{}", reference.number()),
+        };
+        let resp = self
+            .request(reqwest::Method::GET, &self.url(&path))
+            .send()
+            .await?;
+        let status = resp.status();
+        if status == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !status.is_success() {
+            anyhow::bail!(
+                "Could not look up {} on the forge: {}",
+                reference,
+                error_message(status, &resp.text().await.unwrap_or_default())
+            );
+        }
+        Ok(Some(Issue::from_json(reference, &resp.json().await?)))
+    }
+
+    /// Resolves the references in the messages of `commits` and attaches what the
+    /// forge knows about them. The extra context is optional, so a reference that can't
+    /// be looked up (e.g. because of a rate limit) is logged and left out.
+    pub async fn enrich(&self, commits: &mut [Commit]) {
+        let mut wanted: Vec<Reference> = Vec::new();
+        for commit in commits.iter() {
+            for reference in references(&commit.message()) {
+                if !wanted.contains(&reference) {
+                    wanted.push(reference);
+                }
+            }
+        }
+        let issues: Vec<anyhow::Result<Option<Issue>>> =
+            stream::iter(wanted.into_iter().map(|r| self.issue(r)))
+                .buffered(CONCURRENCY)
+                .collect()
+                .await;
+        let issues: Vec<Issue> = issues
+            .into_iter()
+            .filter_map(|issue| {
+                issue
+                    .map_err(|e| log::warn!("{e}; continuing without it"))
+                    .ok()
+                    .flatten()
+            })
+            .collect();
+        for commit in commits {
+            commit.references = references(&commit.message())
+                .into_iter()
+                .filter_map(|r| issues.iter().find(|issue| issue.reference == r).cloned())
+                .collect();
+        }
+    }
+}
+
+/// A request creating or updating a release, built without being sent so it can be
+/// shown for a dry run.
+#[derive(Debug, Clone)]
+pub struct ReleaseRequest {
+    pub method: Method,
+    pub url: String,
+    pub body: Value,
+}
+
+impl fmt::Display for ReleaseRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let body = serde_json::to_string_pretty(&self.body).map_err(|_| fmt::Error)?;
+        write!(f, "{} {}\n{}", self.method, self.url, body)
+    }
+}
+
+/// What to publish as a release.
+#[derive(Debug, Clone, Default)]
+pub struct NewRelease {
+    pub tag: String,
+    pub name: String,
+    /// Markdown shown as the release notes.
+    pub body: String,
+    pub draft: bool,
+    pub prerelease: bool,
+}
+
+impl Forge {
+    /// The request that publishes `release`: an update if the forge already has a
+    /// release for its tag, a new release otherwise.
+    ///
+    /// Looking up the existing release is the only request this sends.
+    pub async fn release_request(&self, release: &NewRelease) -> anyhow::Result<ReleaseRequest> {
+        let tag = release.tag.replace('/', "%2F");
+        let lookup = match self.kind {
+            ForgeKind::GitLab => self.url(&format!("/releases/{tag}")),
+            ForgeKind::GitHub | ForgeKind::Gitea => self.url(&format!("/releases/tags/{tag}")),
+        };
+        let resp = self.request(Method::GET, &lookup).send().await?;
+        let status = resp.status();
+        let existing = match status {
+            StatusCode::NOT_FOUND => None,
+            status if status.is_success() => Some(resp.json::<Value>().await?),
+            status => anyhow::bail!(
+                "Could not look up the release {}: {}",
+                release.tag,
+                error_message(status, &resp.text().await.unwrap_or_default())
+            ),
+        };
+
+        let request = match (self.kind, existing) {
+            (ForgeKind::GitLab, existing) => {
+                let body = json!({
+                    "tag_name": release.tag,
+                    "name": release.name,
+                    "description": release.body,
+                });
+                match existing {
+                    Some(_) => ReleaseRequest {
+                        method: Method::PUT,
+                        url: lookup,
+                        body,
+                    },
+                    None => ReleaseRequest {
+                        method: Method::POST,
+                        url: self.url("/releases"),
+                        body,
+                    },
+                }
+            }
+            (_, existing) => {
+                let body = json!({
+                    "tag_name": release.tag,
+                    "name": release.name,
+                    "body": release.body,
+                    "draft": release.draft,
+                    "prerelease": release.prerelease,
+                });
+                match existing.and_then(|e| e["id"].as_u64()) {
+                    Some(id) => ReleaseRequest {
+                        method: Method::PATCH,
+                        url: self.url(&format!("/releases/{id}")),
+                        body,
+                    },
+                    None => ReleaseRequest {
+                        method: Method::POST,
+                        url: self.url("/releases"),
+                        body,
+                    },
+                }
+            }
+        };
+        Ok(request)
+    }
+
+    /// Sends `req` and returns the web URL of the release, if the forge reports one.
+    pub async fn send(&self, req: &ReleaseRequest) -> anyhow::Result<Option<String>> {
+        if self.token.is_none() {
+            anyhow::bail!("Set {} to publish releases", self.kind.token_var());
+        }
+        let resp = self
+            .request(req.method.clone(), &req.url)
+            .json(&req.body)
+            .send()
+            .await?;
+        let status = resp.status();
+        if !status.is_success() {
+            anyhow::bail!(
+                "Could not publish the release: {}",
+                error_message(status, &resp.text().await.unwrap_or_default())
+            );
+        }
+        let value: Value = resp.json().await.unwrap_or_default();
+        Ok(value["html_url"]
+            .as_str()
+            .or_else(|| value["_links"]["self"].as_str())
+            .map(String::from))
+    }
+}
+
+/// The message of an error response, which every forge puts in `message`.
+pub fn error_message(status: StatusCode, body: &str) -> String {
+    serde_json::from_str::<Value>(body)
+        .ok()
+        .and_then(|value| value["message"].as_str().map(String::from))
+        .map_or_else(
+            || format!("status {status}"),
+            |message| format!("{message} ({status})"),
+        )
+}
+
+/// Splits a remote URL like `git@github.com:owner/name.git` into host and repository path.
+fn parse_remote(url: &str) -> Option<(String, String)> {
+    let url = url.trim();
+    let rest = match url.split_once("://") {
+        Some((_, rest)) => rest,
+        None => url,
+    };
+    let rest = rest.rsplit_once('@').map_or(rest, |(_, rest)| rest);
+    let (host, path) = if url.contains("://") {
+        rest.split_once('/')?
+    } else {
+        rest.split_once(':')?
+    };
+    let host = host.split(':').next()?.to_lowercase();
+    let path = path.trim_matches('/').trim_end_matches(".git").to_string();
+    (!host.is_empty() && path.contains('/')).then_some((host, path))
+}
@@ -0,0 +1,334 @@
+use std::{fmt, fmt::Write, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::git::Commit;
+
+/// How the changelog is written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Format {
+    /// Free-form Markdown, streamed as the model writes it.
+    #[default]
+    Markdown,
+    /// Markdown following <https://keepachangelog.com>.
+    KeepAChangelog,
+    Json,
+    /// A standalone HTML page.
+    Html,
+    Text,
+}
+
+impl Format {
+    /// Whether the model is asked for JSON that is rendered afterwards, rather than for
+    /// the changelog itself.
+    pub fn is_structured(self) -> bool {
+        self != Self::Markdown
+    }
+
+    /// Whether the changelog can be inserted into a Markdown changelog file.
+    pub fn is_markdown(self) -> bool {
+        matches!(self, Self::Markdown | Self::KeepAChangelog)
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "markdown" | "md" => Ok(Self::Markdown),
+            "keep-a-changelog" => Ok(Self::KeepAChangelog),
+            "json" => Ok(Self::Json),
+            "html" => Ok(Self::Html),
+            "text" => Ok(Self::Text),
+            _ => Err(format!(
+                "unknown format {s}, expected one of: markdown, keep-a-changelog, json, html, text"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Markdown => "markdown",
+            Self::KeepAChangelog => "keep-a-changelog",
+            Self::Json => "json",
+            Self::Html => "html",
+            Self::Text => "text",
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Format {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        Self::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+/// The changelog of a release as the model returns it for structured formats; mirrors
+/// [`SCHEMA`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Notes {
+    pub sections: Vec<Section>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Section {
+    pub title: String,
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Entry {
+    pub summary: String,
+    /// Short hashes of the commits the entry is based on.
+    pub commits: Vec<String>,
+    pub breaking: bool,
+}
+
+/// A release ready to be rendered.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Document {
+    pub version: String,
+    /// Date of the newest commit, `YYYY-MM-DD`.
+    pub date: Option<String>,
+    pub sections: Vec<Section>,
+}
+
+/// JSON Schema of [`Notes`], sent to the model.
+pub const SCHEMA: &str = r#"{
+  "type": "object",
+  "required": ["sections"],
+  "additionalProperties": false,
+  "properties": {
+    "sections": {
+      "type": "array",
+      "items": {
+        "type": "object",
+        "required": ["title", "entries"],
+        "additionalProperties": false,
+        "properties": {
+          "title": { "type": "string", "minLength": 1 },
+          "entries": {
+            "type": "array",
+            "minItems": 1,
+            "items": {
+              "type": "object",
+              "required": ["summary", "commits", "breaking"],
+              "additionalProperties": false,
+              "properties": {
+                "summary": { "type": "string", "minLength": 1 },
+                "commits": { "type": "array", "items": { "type": "string" } },
+                "breaking": { "type": "boolean" }
+              }
+            }
+          }
+        }
+      }
+    }
+  }
+}"#;
+
+/// The sections Keep a Changelog allows, in order.
+pub const KEEP_A_CHANGELOG_SECTIONS: [&str; 6] = [
+    "Added",
+    "Changed",
+    "Deprecated",
+    "Removed",
+    "Fixed",
+    "Security",
+];
+
+/// What the system message has to add so the model replies with [`Notes`].
+pub fn instructions(format: Format) -> String {
+    let mut out = format!(
+        "Instead of Markdown, reply with a single JSON object and nothing else, matching this JSON Schema:\n{SCHEMA}\nList the short hashes of the commits each entry is based on in \"commits\" and set \"breaking\" for changes that break compatibility."
+    );
+    if format == Format::KeepAChangelog {
+        let _ = write!(
+            out,
+            " Only use these section titles, in this order: {}.",
+            KEEP_A_CHANGELOG_SECTIONS.join(", ")
+        );
+    }
+    out
+}
+
+/// Parses the reply of the model and checks it against [`SCHEMA`] and the rules of
+/// `format`. Entries may only cite `commits`.
+///
+/// The error describes what is wrong well enough to be sent back to the model.
+pub fn parse(reply: &str, format: Format, commits: &[Commit]) -> anyhow::Result<Notes> {
+    let json = strip_fence(reply.trim());
+    let notes: Notes = serde_json::from_str(json)
+        .map_err(|e| anyhow::anyhow!("The reply is not valid changelog JSON: {e}"))?;
+    for section in &notes.sections {
+        if section.title.trim().is_empty() {
+            anyhow::bail!("A section has an empty title");
+        }
+        if section.entries.is_empty() {
+            anyhow::bail!("Section {:?} has no entries", section.title);
+        }
+        if section.entries.iter().any(|e| e.summary.trim().is_empty()) {
+            anyhow::bail!("Section {:?} has an entry without summary", section.title);
+        }
+        if format == Format::KeepAChangelog
+            && !KEEP_A_CHANGELOG_SECTIONS.contains(&section.title.as_str())
+        {
+            anyhow::bail!(
+                "Section {:?} is not one of {}",
+                section.title,
+                KEEP_A_CHANGELOG_SECTIONS.join(", ")
+            );
+        }
+        for entry in &section.entries {
+            if let Some(hash) = entry.commits.iter().find(|h| !cites(h, commits)) {
+                anyhow::bail!(
+                    "Entry {:?} cites {:?}, which is not the short hash of one of the commits",
+                    entry.summary,
+                    hash
+                );
+            }
+        }
+    }
+    Ok(notes)
+}
+
+fn cites(hash: &str, commits: &[Commit]) -> bool {
+    hash.len() >= 4 && commits.iter().any(|c| c.hash.starts_with(hash))
+}
+
+/// Models like to wrap JSON in a code fence even when told not to.
+fn strip_fence(text: &str) -> &str {
+    let Some(rest) = text.strip_prefix("```") else {
+        return text;
+    };
+    let rest = rest.split_once('\n').map_or("", |(_, rest)| rest);
+    rest.trim_end().strip_suffix("```").unwrap_or(rest).trim()
+}
+
+/// Heading of the release section for `version` in a changelog file.
+pub fn release_heading(format: Format, version: &str, date: Option<&str>) -> String {
+    match (format, date) {
+        (Format::KeepAChangelog, _) if version == "Unreleased" => String::from("[Unreleased]"),
+        (Format::KeepAChangelog, Some(date)) => format!("[{version}] - {date}"),
+        (Format::KeepAChangelog, None) => format!("[{version}]"),
+        _ => version.to_string(),
+    }
+}
+
+/// Writes `document` in `format`.
+///
+/// Markdown and text only contain the sections, like a changelog written by the model;
+/// JSON and HTML are complete documents.
+pub fn render(format: Format, document: &Document) -> String {
+    match format {
+        Format::Markdown | Format::KeepAChangelog => markdown(document),
+        Format::Json => {
+            let mut json = serde_json::to_string_pretty(document).unwrap_or_default();
+            json.push('\n');
+            json
+        }
+        Format::Html => html(document),
+        Format::Text => text(document),
+    }
+}
+
+fn markdown(document: &Document) -> String {
+    let mut out = String::new();
+    for section in &document.sections {
+        let _ = writeln!(out, "### {}\n", section.title);
+        for entry in &section.entries {
+            out.push_str("- ");
+            if entry.breaking {
+                out.push_str("**Breaking:** ");
+            }
+            out.push_str(entry.summary.trim());
+            if !entry.commits.is_empty() {
+                let _ = write!(out, " ({})", entry.commits.join(", "));
+            }
+            out.push('\n');
+        }
+        out.push('\n');
+    }
+    out.truncate(out.trim_end().len());
+    out.push('\n');
+    out
+}
+
+fn text(document: &Document) -> String {
+    let mut out = String::new();
+    for section in &document.sections {
+        let _ = writeln!(
+            out,
+            "{}\n{}\n",
+            section.title,
+            "-".repeat(section.title.chars().count())
+        );
+        for entry in &section.entries {
+            out.push_str("* ");
+            if entry.breaking {
+                out.push_str("BREAKING: ");
+            }
+            out.push_str(entry.summary.trim());
+            if !entry.commits.is_empty() {
+                let _ = write!(out, " ({})", entry.commits.join(", "));
+            }
+            out.push('\n');
+        }
+        out.push('\n');
+    }
+    out.truncate(out.trim_end().len());
+    out.push('\n');
+    out
+}
+
+fn html(document: &Document) -> String {
+    let version = escape(&document.version);
+    let mut out = format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n<title>{version}</title>\n</head>\n<body>\n<h1>{version}</h1>\n"
+    );
+    if let Some(date) = &document.date {
+        let date = escape(date);
+        let _ = writeln!(out, "<p><time datetime=\"{date}\">{date}</time></p>");
+    }
+    for section in &document.sections {
+        let _ = writeln!(out, "<h2>{}</h2>\n<ul>", escape(&section.title));
+        for entry in &section.entries {
+            out.push_str("<li>");
+            if entry.breaking {
+                out.push_str("<strong>Breaking:</strong> ");
+            }
+            out.push_str(&escape(entry.summary.trim()));
+            for commit in &entry.commits {
+                let _ = write!(out, " <code>{}</code>", escape(commit));
+            }
+            out.push_str("</li>\n");
+        }
+        out.push_str("</ul>\n");
+    }
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
@@ -0,0 +1,269 @@
+use std::{
+    fmt::Write,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::forge::Issue;
+
+/// Runs git with `args` in `repo`, any directory inside the work tree, and returns its
+/// stdout.
+fn git(repo: &Path, args: &[&str]) -> anyhow::Result<String> {
+    let output = Command::new("git").args(args).current_dir(repo).output()?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// Root directory of the work tree `repo` is in.
+pub fn toplevel(repo: &Path) -> anyhow::Result<PathBuf> {
+    Ok(PathBuf::from(
+        git(repo, &["rev-parse", "--show-toplevel"])?.trim(),
+    ))
+}
+
+/// Name of the repository, taken from the work tree's directory name.
+pub fn project_name(repo: &Path) -> anyhow::Result<String> {
+    let root = toplevel(repo)?;
+    Ok(root
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default())
+}
+
+/// The most recent tag reachable from HEAD.
+///
+/// Without a pattern this is the tag `git describe` would pick. With a pattern like
+/// `v*` it is the highest version among the matching tags.
+pub fn latest_tag(repo: &Path, pattern: Option<&str>) -> anyhow::Result<Option<String>> {
+    match pattern {
+        // `git describe` fails without a tag; asking for the tags first tells that apart
+        // from real failures without parsing git's (translated) messages.
+        None if tags(repo, None)?.is_empty() => Ok(None),
+        None => Ok(Some(
+            git(repo, &["describe", "--tags", "--abbrev=0"])?
+                .trim()
+                .to_string(),
+        )),
+        Some(_) => Ok(tags(repo, pattern)?.pop()),
+    }
+}
+
+/// Tags reachable from HEAD matching `pattern`, ordered from oldest to newest version.
+pub fn tags(repo: &Path, pattern: Option<&str>) -> anyhow::Result<Vec<String>> {
+    let output = git(
+        repo,
+        &[
+            "tag",
+            "--list",
+            pattern.unwrap_or("*"),
+            "--merged",
+            "HEAD",
+            "--sort=v:refname",
+        ],
+    )?;
+    Ok(output
+        .lines()
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+/// URL of the remote `name`.
+pub fn remote_url(repo: &Path, name: &str) -> anyhow::Result<String> {
+    Ok(git(repo, &["remote", "get-url", name])?.trim().to_string())
+}
+
+/// A single commit from `git log`.
+#[derive(Debug, Clone, Default)]
+pub struct Commit {
+    pub hash: String,
+    pub short_hash: String,
+    pub author: String,
+    pub email: String,
+    /// Author date, `YYYY-MM-DD`.
+    pub date: String,
+    pub subject: String,
+    /// Message body without the trailers.
+    pub body: String,
+    pub trailers: Vec<(String, String)>,
+    pub files: Vec<String>,
+    /// Issues and pull requests the message refers to, filled in by
+    /// [`crate::forge::Forge::enrich`].
+    pub references: Vec<Issue>,
+}
+
+/// Fields of a commit, separated by `%x1f`. The changed files from `--name-only`
+/// follow the last separator.
+const FORMAT: &str =
+    "--format=%x00%H%x1f%h%x1f%an%x1f%ae%x1f%as%x1f%s%x1f%b%x1f%(trailers:only,unfold)%x1f";
+
+/// Maximum number of changed files listed per commit in [`Commit::compact`].
+const MAX_FILES: usize = 10;
+
+/// Commits in `range`, newest first, limited to those touching `paths` if any are given.
+pub fn commits(
+    repo: &Path,
+    range: Option<&str>,
+    paths: &[String],
+    no_merges: bool,
+) -> anyhow::Result<Vec<Commit>> {
+    let mut args = vec!["-c", "core.quotePath=false", "log", FORMAT, "--name-only"];
+    if no_merges {
+        args.push("--no-merges");
+    }
+    if let Some(range) = range {
+        args.push(range);
+    }
+    if !paths.is_empty() {
+        args.push("--");
+        args.extend(paths.iter().map(String::as_str));
+    }
+    git(repo, &args)?
+        .split('\0')
+        .filter(|record| !record.trim().is_empty())
+        .map(Commit::parse)
+        .collect()
+}
+
+impl Commit {
+    fn parse(record: &str) -> anyhow::Result<Self> {
+        let fields: Vec<&str> = record.split('\x1f').collect();
+        let [hash, short_hash, author, email, date, subject, body, trailers, files] = fields[..]
+        else {
+            anyhow::bail!("Unexpected git log output: {record:?}");
+        };
+
+        let trailers: Vec<(String, String)> = trailers
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+            .collect();
+
+        Ok(Self {
+            hash: hash.to_string(),
+            short_hash: short_hash.to_string(),
+            author: author.to_string(),
+            email: email.to_string(),
+            date: date.to_string(),
+            subject: subject.trim().to_string(),
+            body: strip_trailers(body, &trailers),
+            trailers,
+            files: files
+                .lines()
+                .map(str::trim)
+                .filter(|file| !file.is_empty())
+                .map(String::from)
+                .collect(),
+            references: Vec::new(),
+        })
+    }
+
+    /// The full commit message, as `git log --format=%B` would print it.
+    pub fn message(&self) -> String {
+        let mut message = self.subject.clone();
+        if !self.body.is_empty() {
+            message.push_str("\n\n");
+            message.push_str(&self.body);
+        }
+        if !self.trailers.is_empty() {
+            message.push_str("\n\n");
+            let trailers: Vec<String> = self
+                .trailers
+                .iter()
+                .map(|(key, value)| format!("{key}: {value}"))
+                .collect();
+            message.push_str(&trailers.join("\n"));
+        }
+        message
+    }
+
+    /// Token-friendly representation sent to the model.
+    ///
+    /// The first line holds the short hash, date, author and subject; body, trailers and
+    /// changed files follow indented, so every commit starts at column 0.
+    pub fn compact(&self, short: bool) -> String {
+        let mut out = format!(
+            "{} {} {}: {}\n",
+            self.short_hash, self.date, self.author, self.subject
+        );
+        if short {
+            out.push_str(&self.describe_references(short));
+            return out;
+        }
+        for line in self.body.lines() {
+            out.push_str("  ");
+            out.push_str(line);
+            out.push('\n');
+        }
+        for (key, value) in &self.trailers {
+            let _ = writeln!(out, "  {key}: {value}");
+        }
+        if !self.files.is_empty() {
+            let mut files = self.files[..self.files.len().min(MAX_FILES)].join(", ");
+            if self.files.len() > MAX_FILES {
+                let _ = write!(files, " (+{} more)", self.files.len() - MAX_FILES);
+            }
+            let _ = writeln!(out, "  files: {files}");
+        }
+        out.push_str(&self.describe_references(short));
+        out
+    }
+
+    /// Indented lines describing the referenced issues and pull requests, with their
+    /// descriptions unless `short`.
+    pub fn describe_references(&self, short: bool) -> String {
+        let mut out = String::new();
+        for issue in &self.references {
+            let kind = if issue.pull_request {
+                "pull request"
+            } else {
+                "issue"
+            };
+            let _ = write!(out, "  {} {}: {}", kind, issue.reference, issue.title);
+            if !issue.labels.is_empty() {
+                let _ = write!(out, " [{}]", issue.labels.join(", "));
+            }
+            out.push('\n');
+            let summary = issue.summary();
+            if !short && !summary.is_empty() {
+                let _ = writeln!(out, "    {summary}");
+            }
+        }
+        out
+    }
+
+    /// Labels of the referenced issues and pull requests.
+    pub fn labels(&self) -> impl Iterator<Item = &str> {
+        self.references
+            .iter()
+            .flat_map(|issue| issue.labels.iter().map(String::as_str))
+    }
+}
+
+/// Removes the trailer block git includes at the end of `%b`.
+fn strip_trailers(body: &str, trailers: &[(String, String)]) -> String {
+    let body = body.trim();
+    if trailers.is_empty() {
+        return body.to_string();
+    }
+    match body.rsplit_once("\n\n") {
+        Some((rest, last)) if last.lines().all(|line| is_trailer(line, trailers)) => {
+            rest.trim_end().to_string()
+        }
+        None if body.lines().all(|line| is_trailer(line, trailers)) => String::new(),
+        _ => body.to_string(),
+    }
+}
+
+fn is_trailer(line: &str, trailers: &[(String, String)]) -> bool {
+    line.split_once(':')
+        .is_some_and(|(key, _)| trailers.iter().any(|(k, _)| k == key.trim()))
+        || line.starts_with(char::is_whitespace)
+}
@@ -0,0 +1,524 @@
+//! Generates changelogs from Git history with a chat completion model.
+//!
+//! The `aichangelog` binary is a thin wrapper around this crate; release tooling can use
+//! it directly:
+//!
+//! ```no_run
+//! # async fn run() -> anyhow::Result<()> {
+//! use std::path::Path;
+//!
+//! use aichangelog::{filter::Filter, openai::{Model, OpenAi}, Options, Release};
+//!
+//! let provider = OpenAi::new(aichangelog::openai::DEFAULT_BASE_URL, None, Model::Gpt35Turbo);
+//! let release = Release::new(Some(String::from("v1.0.0..v1.1.0")));
+//! let options = Options::default();
+//!
+//! let repo = Path::new(".");
+//! let commits = aichangelog::collect_commits(repo, &release, &Filter::default())?;
+//! let prompt = aichangelog::build_prompt(repo, &provider, &options, &release, &commits).await?;
+//! let changelog = aichangelog::generate_changelog(&provider, &options, &prompt).await?;
+//! println!("{}", changelog.text);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Progress, like summarizing a log too large for the context, and retries are reported
+//! through the [`log`] crate.
+
+use std::path::Path;
+
+use futures::stream::StreamExt;
+
+use crate::{
+    conventional::Section,
+    filter::Filter,
+    format::{Document, Format},
+    git::Commit,
+    openai::{Message, Usage},
+    provider::{ChunkStream, Provider},
+};
+
+pub use provider::Chunk;
+
+pub mod cache;
+pub mod config;
+pub mod conventional;
+pub mod filter;
+pub mod forge;
+pub mod format;
+pub mod git;
+pub mod openai;
+pub mod output;
+pub mod provider;
+pub mod semver;
+pub mod summarize;
+pub mod template;
+pub mod workspace;
+
+/// Settings controlling how commits are turned into a prompt and sent to the model.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Only use the first line of commit messages.
+    pub short: bool,
+    /// Group Conventional Commits into [`Options::sections`] before asking the model.
+    pub conventional: bool,
+    pub sections: Vec<Section>,
+    /// Prompt template; see [`template`] for the placeholders.
+    pub prompt: Option<String>,
+    pub audience: String,
+    /// Defaults to the repository's directory name.
+    pub project_name: Option<String>,
+    pub temp: f64,
+    pub freq: f64,
+    /// Structured formats ask the model for JSON, see [`generate_document`].
+    pub format: Format,
+    /// How often to ask the model to fix JSON that doesn't match the schema.
+    pub repairs: u32,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            short: false,
+            conventional: false,
+            sections: conventional::default_sections(),
+            prompt: None,
+            audience: String::from("developers"),
+            project_name: None,
+            temp: 1.0,
+            freq: 0.0,
+            format: Format::Markdown,
+            repairs: 2,
+        }
+    }
+}
+
+/// A release section to generate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Release {
+    /// Rev range of the release; `None` means all of `HEAD`.
+    pub range: Option<String>,
+    pub heading: String,
+}
+
+impl Release {
+    /// A release for `range`, headed by the revision the range ends at.
+    pub fn new(range: Option<String>) -> Self {
+        Self {
+            heading: release_heading(range.as_deref()),
+            range,
+        }
+    }
+
+    /// Whether the release ends at `HEAD` rather than at a tag.
+    pub fn is_unreleased(&self) -> bool {
+        range_end(self.range.as_deref()).is_none()
+    }
+
+    /// The revision the range starts after, usually the tag of the previous release.
+    pub fn base(&self) -> Option<&str> {
+        let (base, _) = self.range.as_deref()?.split_once("..")?;
+        Some(base).filter(|base| !base.is_empty())
+    }
+
+    /// The unreleased changes since the latest tag of `repo` matching `pattern`.
+    pub fn since_latest_tag(repo: &Path, pattern: Option<&str>) -> anyhow::Result<Self> {
+        let Some(tag) = git::latest_tag(repo, pattern)? else {
+            anyhow::bail!("No tag found to start the range from");
+        };
+        Ok(Self::new(Some(format!("{tag}..HEAD"))))
+    }
+
+    /// The release `tag` of `repo`, starting after the previous tag matching `pattern`.
+    pub fn for_tag(repo: &Path, tag: &str, pattern: Option<&str>) -> anyhow::Result<Self> {
+        let tags = git::tags(repo, pattern)?;
+        let Some(i) = tags.iter().position(|t| t == tag) else {
+            anyhow::bail!("Tag {tag} not found among the tags merged into HEAD");
+        };
+        let range = match i {
+            0 => tag.to_string(),
+            i => format!("{}..{}", tags[i - 1], tag),
+        };
+        Ok(Self {
+            range: Some(range),
+            heading: tag.to_string(),
+        })
+    }
+
+    /// One release per tag of `repo` matching `pattern`, oldest first.
+    pub fn between_tags(repo: &Path, pattern: Option<&str>) -> anyhow::Result<Vec<Self>> {
+        let tags = git::tags(repo, pattern)?;
+        if tags.is_empty() {
+            anyhow::bail!("No tags found to generate sections between");
+        }
+        let mut releases = vec![Self {
+            range: Some(tags[0].clone()),
+            heading: tags[0].clone(),
+        }];
+        releases.extend(tags.windows(2).map(|pair| Self {
+            range: Some(format!("{}..{}", pair[0], pair[1])),
+            heading: pair[1].clone(),
+        }));
+        Ok(releases)
+    }
+}
+
+/// Heading for the release section, taken from the end of the rev range.
+fn release_heading(range: Option<&str>) -> String {
+    range_end(range).map_or_else(|| String::from("Unreleased"), String::from)
+}
+
+/// The revision `range` ends at, `None` for `HEAD`.
+fn range_end(range: Option<&str>) -> Option<&str> {
+    let range = range?;
+    let rev = range
+        .rsplit("..")
+        .next()
+        .unwrap_or(range)
+        .trim_start_matches('.');
+    (!rev.is_empty() && rev != "HEAD").then_some(rev)
+}
+
+/// Reads the commits of `release` in `repo` that pass `filter`, newest first.
+pub fn collect_commits(
+    repo: &Path,
+    release: &Release,
+    filter: &Filter,
+) -> anyhow::Result<Vec<Commit>> {
+    filter.commits(repo, release.range.as_deref())
+}
+
+/// The messages to send for a release.
+#[derive(Debug, Clone)]
+pub struct Prompt {
+    pub messages: Vec<Message>,
+    /// Tokens of [`Prompt::messages`], counted locally.
+    pub tokens: usize,
+    /// What summarizing a log too large for the context already used.
+    pub usage: Usage,
+}
+
+/// Builds the prompt for `commits` of `repo`, summarizing them first if they don't fit
+/// the provider's context. The project name is read from `repo` unless
+/// [`Options::project_name`] is set.
+pub async fn build_prompt(
+    repo: &Path,
+    provider: &dyn Provider,
+    options: &Options,
+    release: &Release,
+    commits: &[Commit],
+) -> anyhow::Result<Prompt> {
+    let (prompt, _) = assemble_prompt(repo, provider, options, release, commits, true).await?;
+    Ok(prompt)
+}
+
+/// The prompt [`build_prompt`] would send, assembled without calling the model.
+#[derive(Debug, Clone)]
+pub struct Preview {
+    pub prompt: Prompt,
+    /// Whether the commits fit the context; if not, [`build_prompt`] summarizes them
+    /// first and [`Preview::prompt`] holds them unabridged.
+    pub fits: bool,
+}
+
+/// Assembles the prompt for `commits` like [`build_prompt`] but never summarizes them.
+pub async fn preview_prompt(
+    repo: &Path,
+    provider: &dyn Provider,
+    options: &Options,
+    release: &Release,
+    commits: &[Commit],
+) -> anyhow::Result<Preview> {
+    let (prompt, fits) = assemble_prompt(repo, provider, options, release, commits, false).await?;
+    Ok(Preview { prompt, fits })
+}
+
+/// Builds the prompt and tells whether the commits fit the context without summarizing
+/// them; with `summarize` they are summarized if they don't.
+async fn assemble_prompt(
+    repo: &Path,
+    provider: &dyn Provider,
+    options: &Options,
+    release: &Release,
+    commits: &[Commit],
+    summarize: bool,
+) -> anyhow::Result<(Prompt, bool)> {
+    let (output, default_system) = if options.conventional {
+        (
+            conventional::group(commits, &options.sections, options.short),
+            conventional::SYSTEM_MSG,
+        )
+    } else {
+        let log: String = commits.iter().map(|c| c.compact(options.short)).collect();
+        (log, SYSTEM_MSG)
+    };
+    if output.trim().is_empty() {
+        anyhow::bail!(
+            "No commits found in {}",
+            release.range.as_deref().unwrap_or("HEAD")
+        );
+    }
+
+    let project_name = match &options.project_name {
+        Some(name) => name.clone(),
+        None => git::project_name(repo)?,
+    };
+    let date = commits.first().map(|c| c.date.as_str()).unwrap_or_default();
+    let vars = |commits: &str| -> anyhow::Result<String> {
+        let template = options.prompt.as_deref().unwrap_or_default();
+        template::render(
+            template,
+            &[
+                ("project_name", &project_name),
+                ("version", &release.heading),
+                ("date", date),
+                ("audience", &options.audience),
+                ("commits", commits),
+            ],
+        )
+    };
+
+    // A template that embeds {{commits}} becomes the user message, otherwise it
+    // replaces the system message and the commits are sent on their own.
+    let embeds_commits = options
+        .prompt
+        .as_deref()
+        .is_some_and(|t| template::uses(t, "commits"));
+    let system_msg = match &options.prompt {
+        Some(_) if !embeds_commits => vars("")?,
+        _ => String::from(default_system),
+    };
+    let mut budget_msg = if embeds_commits {
+        format!("{}\n{}", system_msg, vars("")?)
+    } else {
+        system_msg.clone()
+    };
+    if options.format.is_structured() {
+        budget_msg = format!("{}\n\n{}", budget_msg, format::instructions(options.format));
+    }
+
+    let fits = provider.count_tokens(&output)? <= summarize::budget(provider, &budget_msg)?;
+    let reduced = if summarize {
+        summarize::reduce(output, &budget_msg, provider, options.temp, options.freq).await?
+    } else {
+        summarize::Reduced {
+            content: output,
+            summarized: false,
+            usage: Usage::default(),
+        }
+    };
+    let system_msg = if reduced.summarized && options.prompt.is_none() {
+        String::from(summarize::MERGE_MSG)
+    } else {
+        system_msg
+    };
+    let system_msg = if options.format.is_structured() {
+        format!("{}\n\n{}", system_msg, format::instructions(options.format))
+    } else {
+        system_msg
+    };
+    let user_msg = if embeds_commits {
+        vars(&reduced.content)?
+    } else {
+        reduced.content
+    };
+    let tokens = provider.count_tokens(&system_msg)? + provider.count_tokens(&user_msg)?;
+
+    let prompt = Prompt {
+        messages: vec![Message::system(system_msg), Message::user(user_msg)],
+        tokens,
+        usage: reduced.usage,
+    };
+    Ok((prompt, fits))
+}
+
+/// Streams the changelog for `prompt` as it is generated.
+///
+/// An error the server reports inside the stream ends it, for OpenAI with an
+/// [`openai::Error`].
+pub async fn stream_changelog(
+    provider: &dyn Provider,
+    options: &Options,
+    prompt: &Prompt,
+) -> anyhow::Result<ChunkStream> {
+    let req = openai::Request::new(
+        provider.model(),
+        prompt.messages.clone(),
+        1,
+        options.temp,
+        options.freq,
+    );
+    provider.stream(&req).await
+}
+
+/// A generated changelog.
+#[derive(Debug, Clone)]
+pub struct Changelog {
+    pub text: String,
+    /// Tokens used for the changelog and any summarizing before it.
+    pub usage: Usage,
+}
+
+/// Generates the changelog for `prompt` and waits for it to finish.
+pub async fn generate_changelog(
+    provider: &dyn Provider,
+    options: &Options,
+    prompt: &Prompt,
+) -> anyhow::Result<Changelog> {
+    let mut stream = stream_changelog(provider, options, prompt).await?;
+    let mut text = String::new();
+    let mut usage = None;
+    while let Some(chunk) = stream.next().await {
+        match chunk? {
+            Chunk::Delta(delta) => text.push_str(&delta),
+            Chunk::Usage(u) => usage = Some(u),
+        }
+    }
+    let mut usage = match usage {
+        Some(usage) => usage,
+        None => {
+            let completion_tokens = provider.count_tokens(&text)?;
+            Usage {
+                prompt_tokens: prompt.tokens,
+                completion_tokens,
+                total_tokens: prompt.tokens + completion_tokens,
+            }
+        }
+    };
+    usage.add(&prompt.usage);
+    Ok(Changelog { text, usage })
+}
+
+/// A changelog generated in a structured format.
+#[derive(Debug, Clone)]
+pub struct StructuredChangelog {
+    pub document: Document,
+    /// Tokens used for the changelog, including repairs and any summarizing before it.
+    pub usage: Usage,
+    /// How often the model had to be asked to fix its reply.
+    pub repairs: u32,
+}
+
+/// Generates the changelog for `prompt` as JSON and parses it into a [`Document`] for
+/// `release`, ready to be rendered with [`format::render`].
+///
+/// A reply that doesn't parse or cites unknown commits is sent back to the model with
+/// what is wrong with it, up to [`Options::repairs`] times.
+///
+/// `prompt` has to be built with a structured [`Options::format`].
+pub async fn generate_document(
+    provider: &dyn Provider,
+    options: &Options,
+    release: &Release,
+    commits: &[Commit],
+    prompt: &Prompt,
+) -> anyhow::Result<StructuredChangelog> {
+    let mut prompt = prompt.clone();
+    let mut usage = Usage::default();
+    let mut repairs = 0;
+    loop {
+        let changelog = generate_changelog(provider, options, &prompt).await?;
+        usage.add(&changelog.usage);
+        let err = match format::parse(&changelog.text, options.format, commits) {
+            Ok(notes) => {
+                return Ok(StructuredChangelog {
+                    document: Document {
+                        version: release.heading.clone(),
+                        date: commits.first().map(|c| c.date.clone()),
+                        sections: notes.sections,
+                    },
+                    usage,
+                    repairs,
+                })
+            }
+            Err(err) if repairs == options.repairs => return Err(err),
+            Err(err) => err,
+        };
+
+        let correction = format!("{err}. {REPAIR_MSG}");
+        prompt.tokens +=
+            provider.count_tokens(&changelog.text)? + provider.count_tokens(&correction)?;
+        prompt.messages.push(Message::assistant(changelog.text));
+        prompt.messages.push(Message::user(correction));
+        // Already counted with the first reply.
+        prompt.usage = Usage::default();
+        repairs += 1;
+    }
+}
+
+/// Alternative changelogs for the same prompt.
+#[derive(Debug, Clone)]
+pub struct Candidates {
+    pub texts: Vec<String>,
+    /// Tokens used for all candidates and any summarizing before them.
+    pub usage: Usage,
+}
+
+/// Generates `n` changelogs for `prompt` in a single request, so the prompt is only
+/// billed once.
+pub async fn generate_candidates(
+    provider: &dyn Provider,
+    options: &Options,
+    prompt: &Prompt,
+    n: u32,
+) -> anyhow::Result<Candidates> {
+    let req = openai::Request::new(
+        provider.model(),
+        prompt.messages.clone(),
+        n as i32,
+        options.temp,
+        options.freq,
+    )
+    .stream(false);
+    let mut resp = provider.complete(&req).await?;
+    resp.choices.sort_by_key(|choice| choice.index);
+    let texts: Vec<String> = resp
+        .choices
+        .into_iter()
+        .map(|choice| choice.message.content)
+        .collect();
+    if texts.is_empty() {
+        anyhow::bail!("The model returned no changelog");
+    }
+    let mut usage = match resp.usage {
+        Some(usage) => usage,
+        None => {
+            let mut completion_tokens = 0;
+            for text in &texts {
+                completion_tokens += provider.count_tokens(text)?;
+            }
+            Usage {
+                prompt_tokens: prompt.tokens,
+                completion_tokens,
+                total_tokens: prompt.tokens + completion_tokens,
+            }
+        }
+    };
+    usage.add(&prompt.usage);
+    Ok(Candidates { texts, usage })
+}
+
+/// The prompt asking the model to merge `candidates` into a single changelog.
+pub fn merge_prompt(provider: &dyn Provider, candidates: &[String]) -> anyhow::Result<Prompt> {
+    let user_msg: String = candidates
+        .iter()
+        .enumerate()
+        .map(|(i, text)| format!("Changelog {}:\n\n{}\n\n", i + 1, text.trim()))
+        .collect();
+    let tokens = provider.count_tokens(CANDIDATES_MSG)? + provider.count_tokens(&user_msg)?;
+    Ok(Prompt {
+        messages: vec![
+            Message::system(String::from(CANDIDATES_MSG)),
+            Message::user(user_msg),
+        ],
+        tokens,
+        usage: Usage::default(),
+    })
+}
+
+const SYSTEM_MSG: &str = r#"You are now an AI that takes a range of Git commit messages as input and generates a changelog in the style of update notes using Markdown formatting. Each commit starts with its short hash, date, author and subject, optionally followed by its indented description, trailers and changed files."#;
+
+const CANDIDATES_MSG: &str = r#"You are now an AI that takes several changelogs written for the same release as input and merges them into one changelog in the style of update notes using Markdown formatting. Keep every change mentioned in any of them exactly once, prefer the clearest wording and follow the structure of the first one."#;
+
+const REPAIR_MSG: &str =
+    r#"Reply with the corrected JSON object only, matching the JSON Schema from before."#;
@@ -1,194 +1,804 @@
-use std::{env, process, time::Duration};
+use std::{
+    collections::HashMap,
+    env, fs,
+    io::{self, Write},
+    path::PathBuf,
+    process,
+};
 
-use clap::Parser;
+use clap::{parser::ValueSource, ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
 use colored::Colorize;
-use crossterm::{
-    cursor::{self, MoveToColumn, MoveToPreviousLine},
-    execute,
-    style::{Color, Print, ResetColor, SetForegroundColor},
-    terminal::{self, Clear, ClearType},
-};
 use futures::stream::StreamExt;
-use reqwest_eventsource::{Event, EventSource};
-use unicode_segmentation::UnicodeSegmentation;
 
-use crate::openai::Message;
+use aichangelog::{
+    cache::{self, Cache},
+    config, conventional,
+    filter::{Filter, FilterConfig},
+    forge::{Forge, ForgeConfig, NewRelease},
+    format::{self, Format},
+    git::{self, Commit},
+    openai::{self, Message, Role, Usage},
+    output,
+    provider::Provider,
+    semver::{self, Bump},
+    workspace::{self, Package, PackageConfig},
+    Chunk, Options, Prompt, Release,
+};
+
+use crate::render::Renderer;
 
-mod openai;
+mod render;
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let Ok(api_key) = env::var("OPENAI_API_KEY") else {
-        println!("{} {}", "OPENAI_API_KEY not set.".red(), "Refer to step 3 here: https://help.openai.com/en/articles/5112595-best-practices-for-api-key-safety".bright_black());
+async fn main() {
+    let matches = Args::command().get_matches();
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if let Err(e) = run(&matches, args).await {
+        eprintln!("Error: {}", e);
         process::exit(1);
-    };
+    }
+}
 
-    let args = Args::parse();
+async fn run(matches: &ArgMatches, mut args: Args) -> anyhow::Result<()> {
+    args.repo = env::current_dir()?;
+    args.apply(matches, config::Config::load(&args.repo)?);
+    if let Some(path) = &args.prompt_file {
+        let prompt = fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Could not read {}: {}", path.display(), e))?;
+        args.prompt = Some(prompt);
+    }
+    if !render::is_interactive(args.plain) {
+        colored::control::set_override(false);
+    }
+    if log::set_logger(&Logger).is_ok() {
+        log::set_max_level(log::LevelFilter::Info);
+    }
 
-    let mut cmd = process::Command::new("git");
-    cmd.arg("log");
-    if args.short {
-        cmd.arg("--oneline");
+    if let Some(Command::Cache {
+        action: CacheCommand::Clear,
+    }) = &args.command
+    {
+        let cache = Cache::new()?;
+        let removed = cache
+            .clear()
+            .map_err(|e| anyhow::anyhow!("Could not clear the cache: {}", e))?;
+        println!(
+            "Removed {} cached changelogs from {}",
+            removed,
+            cache.dir().display()
+        );
+        return Ok(());
     }
-    if let Some(range) = args.range {
-        cmd.arg(range);
+
+    let api_key = env::var("OPENAI_API_KEY").ok();
+    let needs_model = (!args.next_version || args.check_bump) && !args.dry_run;
+    if api_key.is_none() && needs_model && args.base_url == openai::DEFAULT_BASE_URL {
+        anyhow::bail!(
+            "{} {}",
+            "OPENAI_API_KEY not set.".red(),
+            "Refer to step 3 here: https://help.openai.com/en/articles/5112595-best-practices-for-api-key-safety".bright_black()
+        );
     }
-    let output = match cmd.output() {
-        Ok(output) => String::from_utf8(output.stdout).expect("Failed to parse output"),
-        Err(e) => {
-            eprintln!("Error: {}", e);
-            process::exit(1);
+    let provider = openai::OpenAi::new(&args.base_url, api_key, args.model.clone())
+        .context_size(args.context_size)
+        .pricing(args.pricing.get(&args.model.to_string()).copied())
+        .retries(args.retries);
+
+    let forge = args.forge()?;
+    for target in &targets(&args)? {
+        let releases = releases(&args, target)?;
+        if target.output.is_some() && releases.len() > 1 && !args.format.is_markdown() {
+            anyhow::bail!(
+                "--format {} can only write one release to --output",
+                args.format
+            );
+        }
+
+        let mut package_heading = target.package.as_ref().map(|p| p.name.as_str());
+        for release in &releases {
+            let mut commits = aichangelog::collect_commits(&args.repo, release, &target.filter)?;
+            // Most packages of a monorepo don't change in every release.
+            if let (Some(package), true) = (&target.package, commits.is_empty()) {
+                eprintln!(
+                    "{}",
+                    format!("No changes to {} in {}", package.name, release.heading).bright_black()
+                );
+                continue;
+            }
+
+            if let Some(forge) = &forge {
+                forge.enrich(&mut commits).await;
+            }
+
+            let release = if args.bump || args.next_version {
+                bump(&args, &provider, target, release, &commits).await?
+            } else {
+                release.clone()
+            };
+            if args.next_version {
+                match &target.package {
+                    Some(package) => println!("{} {}", package.name, release.heading),
+                    None => println!("{}", release.heading),
+                }
+                continue;
+            }
+
+            let heading = format::release_heading(
+                args.format,
+                &release.heading,
+                commits.first().map(|c| c.date.as_str()),
+            );
+            if let Some(name) = package_heading.take() {
+                println!("{}\n", format!("# {}", name).bold());
+            }
+            if releases.len() > 1 {
+                println!("{}\n", format!("## {}", heading).bold());
+            }
+            if args.dry_run {
+                preview(&args, &provider, target, &release, &commits).await?;
+                continue;
+            }
+            let mut changelog = generate(&args, &provider, target, &release, &commits).await?;
+            if args.edit {
+                changelog = edit(args.format, &changelog)?;
+            }
+            // Drafts went to stderr, so only the version the user kept ends up on stdout.
+            if (args.refine || args.edit) && !render::is_interactive(args.plain) {
+                print!("{changelog}");
+                if !changelog.ends_with('\n') {
+                    println!();
+                }
+            }
+
+            if let Some(path) = &target.output {
+                let written = if args.format.is_markdown() {
+                    output::insert_release(path, &heading, &changelog)
+                } else {
+                    output::write(path, &changelog)
+                };
+                written
+                    .map_err(|e| anyhow::anyhow!("Could not write {}: {}", path.display(), e))?;
+                eprintln!(
+                    "{}",
+                    format!("Changelog written to {}", path.display()).bright_black()
+                );
+            }
+
+            if let Some(Command::Publish(publish)) = &args.command {
+                publish_release(&args, publish, &changelog).await?;
+            }
         }
+    }
+
+    Ok(())
+}
+
+/// Lets the user edit `changelog` in `$VISUAL` or `$EDITOR`, like `git commit` does.
+fn edit(format: Format, changelog: &str) -> anyhow::Result<String> {
+    let extension = match format {
+        Format::Markdown | Format::KeepAChangelog => "md",
+        Format::Json => "json",
+        Format::Html => "html",
+        Format::Text => "txt",
+    };
+    // A fresh file only we can access, so nobody can swap it for a symlink.
+    let mut file = tempfile::Builder::new()
+        .prefix("aichangelog-")
+        .suffix(&format!(".{extension}"))
+        .tempfile()?;
+    file.write_all(changelog.as_bytes())?;
+    file.flush()?;
+    let path = file.into_temp_path();
+
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| String::from("vi"));
+    // Run through the shell, so editors with arguments like `code --wait` work.
+    let status = process::Command::new("sh")
+        .args(["-c", &format!("{editor} \"$@\""), &editor])
+        .arg(&path)
+        .status();
+    let edited = fs::read_to_string(&path);
+    let _ = path.close();
+
+    match status {
+        Ok(status) if status.success() => {}
+        Ok(status) => anyhow::bail!("Editor {editor} exited with {status}"),
+        Err(e) => anyhow::bail!("Could not run editor {editor}: {e}"),
+    }
+    let edited = edited?;
+    if edited.trim().is_empty() {
+        anyhow::bail!("Aborting because the changelog is empty");
+    }
+    Ok(edited)
+}
+
+/// Creates or updates the release of `publish.tag` with `changelog` as its notes.
+async fn publish_release(
+    args: &Args,
+    publish: &PublishArgs,
+    changelog: &str,
+) -> anyhow::Result<()> {
+    let forge = Forge::detect(&args.repo, &args.forge_config)?;
+    let release = NewRelease {
+        tag: publish.tag.clone(),
+        name: publish.name.clone().unwrap_or_else(|| publish.tag.clone()),
+        body: changelog.trim().to_string(),
+        draft: publish.draft,
+        prerelease: publish.prerelease,
     };
+    let req = forge.release_request(&release).await?;
+    if publish.dry_run {
+        println!("\n{req}");
+        return Ok(());
+    }
+    match forge.send(&req).await? {
+        Some(url) => eprintln!("{}", format!("Release published at {url}").bright_black()),
+        None => eprintln!("{}", "Release published".bright_black()),
+    }
+    Ok(())
+}
+
+/// What a changelog is generated for: the whole repository or one of its packages.
+struct Target {
+    package: Option<Package>,
+    filter: Filter,
+    tag_pattern: Option<String>,
+    output: Option<PathBuf>,
+}
 
-    let prompt_tokens = openai::count_token(&output)?;
-    if prompt_tokens > args.model.context_size() {
+/// The repository, or with `--workspace` each selected package of it.
+fn targets(args: &Args) -> anyhow::Result<Vec<Target>> {
+    let filter = Filter::new(&args.filters)?;
+    if !args.workspace {
+        return Ok(vec![Target {
+            package: None,
+            filter,
+            tag_pattern: args.tag_pattern.clone(),
+            output: args.output.clone(),
+        }]);
+    }
+
+    // Each package only looks at its own path.
+    if !args.paths.is_empty() {
+        anyhow::bail!("Paths after -- can't be combined with workspace mode; pass --no-workspace");
+    }
+    // Every package gets its own file, so the path must be relative to the package.
+    if let Some(output) = args.output.as_ref().filter(|output| output.is_absolute()) {
+        anyhow::bail!(
+            "--output {} must be relative in workspace mode, it is resolved against each package",
+            output.display()
+        );
+    }
+    let packages = workspace::packages(&args.repo, &args.packages)?;
+    if let Some(name) = args
+        .package
+        .iter()
+        .find(|name| !packages.iter().any(|p| &p.name == *name))
+    {
+        let known: Vec<&str> = packages.iter().map(|p| p.name.as_str()).collect();
+        anyhow::bail!(
+            "Unknown package {name}, expected one of: {}",
+            known.join(", ")
+        );
+    }
+    let root = git::toplevel(&args.repo)?;
+    let targets: Vec<Target> = packages
+        .into_iter()
+        .filter(|p| args.package.is_empty() || args.package.contains(&p.name))
+        .map(|package| Target {
+            filter: package.filter(&filter),
+            tag_pattern: Some(package.tag_pattern.clone()),
+            output: args
+                .output
+                .as_ref()
+                .map(|output| root.join(&package.path).join(output)),
+            package: Some(package),
+        })
+        .collect();
+    if matches!(args.command, Some(Command::Publish(_))) && targets.len() > 1 {
+        anyhow::bail!("Choose the package to publish with --package");
+    }
+    Ok(targets)
+}
+
+/// Works out which releases of `target` to generate sections for, oldest first.
+fn releases(args: &Args, target: &Target) -> anyhow::Result<Vec<Release>> {
+    let pattern = target.tag_pattern.as_deref();
+    if let Some(Command::Publish(publish)) = &args.command {
+        return match &args.range {
+            Some(range) => Ok(vec![Release {
+                range: Some(range.clone()),
+                heading: publish.tag.clone(),
+            }]),
+            None => Ok(vec![Release::for_tag(&args.repo, &publish.tag, pattern)?]),
+        };
+    }
+    if args.between_tags {
+        Release::between_tags(&args.repo, pattern)
+    } else if args.latest || args.next_version {
+        // A package that was never released: all of its history is unreleased.
+        if target.package.is_some() && git::latest_tag(&args.repo, pattern)?.is_none() {
+            return Ok(vec![Release::new(None)]);
+        }
+        Ok(vec![Release::since_latest_tag(&args.repo, pattern)?])
+    } else {
+        Ok(vec![Release::new(args.range.clone())])
+    }
+}
+
+/// `release` headed by the next semantic version if it is unreleased.
+async fn bump(
+    args: &Args,
+    provider: &dyn Provider,
+    target: &Target,
+    release: &Release,
+    commits: &[Commit],
+) -> anyhow::Result<Release> {
+    if !release.is_unreleased() {
+        return Ok(release.clone());
+    }
+    // Nothing to release; a patch bump would tag the same commit again.
+    if commits.is_empty() {
+        anyhow::bail!("No commits since {}", release.base().unwrap_or("HEAD"));
+    }
+    let bump = Bump::of(commits);
+    if args.check_bump && !args.dry_run {
+        match semver::ask_model(provider, commits, args.temp).await? {
+            Some(suggested) if suggested != bump => eprintln!(
+                "{}",
+                format!(
+                    "The model suggests a {suggested} release, Conventional Commits a {bump} one"
+                )
+                .yellow()
+            ),
+            Some(_) => {}
+            None => eprintln!("{}", "The model gave no version bump".yellow()),
+        }
+    }
+    let version = semver::next_version(release.base(), target.tag_pattern.as_deref(), bump)?;
+    if !args.next_version {
         eprintln!(
-            "Error: Git log is too long. Prompt is {} tokens, but the maximum is {}.\nTry using a smaller range or the -s flag.",
-            format!("{}", prompt_tokens).purple(),
-            format!("{}", args.model.context_size()).purple()
+            "{}",
+            format!("Next version: {version} ({bump})").bright_black()
         );
-        process::exit(1);
     }
+    Ok(Release {
+        range: release.range.clone(),
+        heading: version.to_string(),
+    })
+}
 
-    let messages = vec![
-        Message::system(String::from(SYSTEM_MSG)),
-        Message::user(output),
-    ];
+/// Prints the prompt for `release` and what sending it would cost, without sending it.
+async fn preview(
+    args: &Args,
+    provider: &dyn Provider,
+    target: &Target,
+    release: &Release,
+    commits: &[Commit],
+) -> anyhow::Result<()> {
+    let options = args.options(target);
+    let preview =
+        aichangelog::preview_prompt(&args.repo, provider, &options, release, commits).await?;
+    for message in &preview.prompt.messages {
+        let role = match message.role {
+            Role::System => "System",
+            Role::User => "User",
+            Role::Assistant => "Assistant",
+        };
+        let tokens = provider.count_tokens(&message.content)?;
+        println!(
+            "{}\n\n{}\n",
+            format!("{role} message ({tokens} tokens)").bold(),
+            message.content.trim_end()
+        );
+    }
 
-    let req = openai::Request::new(
-        args.model.clone().to_string(),
-        messages,
-        1,
-        args.temp,
-        args.freq,
+    let tokens = preview.prompt.tokens;
+    let context = provider.context_size();
+    println!(
+        "Prompt: {} tokens, {:.1}% of the {} token context of {}",
+        tokens,
+        tokens as f64 * 100.0 / context as f64,
+        context,
+        provider.model()
     );
+    // At worst every reply fills the rest of the context.
+    let completion = context.saturating_sub(tokens) * args.candidates as usize;
+    match provider.cost(tokens, completion) {
+        Some(cost) => println!("Worst-case cost: {}", format!("~${cost:0.4}").purple()),
+        None => println!("Worst-case {}", unknown_cost(provider).yellow()),
+    }
+    if !preview.fits {
+        println!(
+            "{}",
+            "The commits don't fit the context and would be summarized first, at extra cost"
+                .yellow()
+        );
+    }
+    Ok(())
+}
 
-    let json = match serde_json::to_string(&req) {
-        Ok(json) => json,
-        Err(e) => {
-            println!("{e}");
-            process::exit(1);
-        }
+/// Generates the changelog for `release`, streaming it to the terminal.
+async fn generate(
+    args: &Args,
+    provider: &dyn Provider,
+    target: &Target,
+    release: &Release,
+    commits: &[Commit],
+) -> anyhow::Result<String> {
+    if args.candidates > 1 && args.format.is_structured() {
+        anyhow::bail!("--candidates only works with --format markdown");
+    }
+    if args.refine && args.format.is_structured() {
+        anyhow::bail!("--refine only works with --format markdown");
+    }
+    let options = args.options(target);
+
+    let cache = if args.no_cache {
+        None
+    } else {
+        Cache::new().ok()
     };
+    let key = cache::key(provider, &args.base_url, &options, release, commits);
+    // Asking for candidates or to refine the changelog means wanting a new one.
+    let cached = cache
+        .as_ref()
+        .filter(|_| args.candidates == 1 && !args.refine)
+        .and_then(|cache| cache.get(&key));
+    if let Some(changelog) = cached {
+        let banner = format!(
+            "{}",
+            "Loaded from the cache; use --no-cache to generate it again".bright_black()
+        );
+        show(args, &changelog, &banner)?;
+        return Ok(changelog);
+    }
 
-    let request_builder = reqwest::Client::new()
-        .post("https://api.openai.com/v1/chat/completions")
-        .header("Content-Type", "application/json")
-        .bearer_auth(api_key)
-        .body(json);
-
-    let loading_ai_animation = tokio::spawn(async {
-        let emoji_support =
-            terminal_supports_emoji::supports_emoji(terminal_supports_emoji::Stream::Stdout);
-        let frames = if emoji_support {
-            vec![
-                "🕛", "🕐", "🕑", "🕒", "🕓", "🕔", "🕕", "🕖", "🕗", "🕘", "🕙", "🕚",
-            ]
+    let prompt =
+        aichangelog::build_prompt(&args.repo, provider, &options, release, commits).await?;
+    let changelog = if options.format.is_structured() {
+        generate_structured(args, provider, &options, release, commits, &prompt).await?
+    } else if args.candidates > 1 {
+        pick_candidate(args, provider, &options, &prompt).await?
+    } else {
+        let mut renderer = new_renderer(args)?;
+        let changelog = stream_changelog(&mut renderer, provider, &options, &prompt).await?;
+        if args.refine {
+            refine(args, provider, &options, prompt, changelog, renderer).await?
         } else {
-            vec!["/", "-", "\\", "|"]
-        };
-        let mut current_frame = 0;
-        let mut stdout = std::io::stdout();
-        loop {
-            current_frame = (current_frame + 1) % frames.len();
-            match execute!(
-                stdout,
-                Clear(ClearType::CurrentLine),
-                MoveToColumn(0),
-                SetForegroundColor(Color::Yellow),
-                Print("Asking AI "),
-                Print(frames[current_frame]),
-                ResetColor
-            ) {
-                Ok(_) => {}
-                Err(_) => {
-                    break;
-                }
-            }
-            tokio::time::sleep(Duration::from_millis(150)).await;
+            changelog
         }
-    });
+    };
 
-    let term_width = terminal::size()?.0 as usize;
+    if let Some(cache) = &cache {
+        if let Err(e) = cache.put(&key, &provider.model(), release, &changelog) {
+            eprintln!("{}", format!("Could not cache the changelog: {e}").yellow());
+        }
+    }
 
-    let mut stdout = std::io::stdout();
+    Ok(changelog)
+}
+
+/// Streams the changelog for `prompt` to the terminal.
+async fn stream_changelog(
+    renderer: &mut Renderer,
+    provider: &dyn Provider,
+    options: &Options,
+    prompt: &Prompt,
+) -> anyhow::Result<String> {
+    let banner = |prompt_tokens: usize, response_tokens: usize| {
+        cost_banner(
+            provider,
+            prompt_tokens + prompt.usage.prompt_tokens,
+            response_tokens + prompt.usage.completion_tokens,
+        )
+    };
 
     let mut changelog = String::new();
 
-    let mut es = EventSource::new(request_builder)?;
-    let mut lines_to_move_up = 0;
+    let mut stream = aichangelog::stream_changelog(provider, options, prompt).await?;
     let mut response_tokens = 0;
-    while let Some(event) = es.next().await {
-        if !loading_ai_animation.is_finished() {
-            loading_ai_animation.abort();
-            execute!(
-                std::io::stdout(),
-                Clear(ClearType::CurrentLine),
-                MoveToColumn(0),
-            )?;
-            print!("\n\n")
-        }
-
-        execute!(
-            stdout,
-            cursor::SavePosition,
-            MoveToPreviousLine(lines_to_move_up),
-        )?;
-        lines_to_move_up = 0;
-        match event {
-            Ok(Event::Message(message)) => {
-                if message.data == "[DONE]" {
-                    break;
-                }
-                execute!(stdout, Clear(ClearType::FromCursorDown),)?;
-                let resp = serde_json::from_str::<openai::Response>(&message.data)
-                    .map_or_else(|_| openai::Response::default(), |r| r);
-                if let Some(delta) = &resp.choices[0].delta.content {
-                    changelog.push_str(delta);
-                    response_tokens += 1;
-                }
-                let outp = format!(
-                    "{}{}\n{}\n",
-                    Print(format!("{}\n", "=======================").bright_black()),
-                    format!(
-                        "This used {} tokens costing you about {}\n",
-                        format!("{}", response_tokens + prompt_tokens).purple(),
-                        format!("~${:0.4}", args.model.cost(prompt_tokens, response_tokens))
-                            .purple()
-                    ),
-                    changelog,
-                );
-                print!("{outp}");
-                lines_to_move_up += count_lines(&outp, term_width) - 1;
-            }
-            Err(e) => {
-                println!("{e}");
-                process::exit(1);
+    let mut usage = None;
+    while let Some(chunk) = stream.next().await {
+        renderer.next_event()?;
+        match chunk? {
+            Chunk::Delta(delta) => {
+                changelog.push_str(&delta);
+                response_tokens += 1;
+                renderer.update(&changelog, &delta, &banner(prompt.tokens, response_tokens))?;
             }
-            _ => {}
+            Chunk::Usage(u) => usage = Some(u),
         }
     }
 
-    execute!(
-        stdout,
-        cursor::RestorePosition,
-        Print(format!("{}\n", "=======================").bright_black()),
-    )?;
+    // Counting deltas is only an estimate; prefer what the server billed.
+    let (prompt_tokens, response_tokens) = match usage {
+        Some(usage) => (usage.prompt_tokens, usage.completion_tokens),
+        None => (prompt.tokens, provider.count_tokens(&changelog)?),
+    };
+    renderer.finish(&changelog, &banner(prompt_tokens, response_tokens))?;
+    Ok(changelog)
+}
 
-    Ok(())
+/// Generates the changelog as JSON and shows it rendered in `--format`.
+async fn generate_structured(
+    args: &Args,
+    provider: &dyn Provider,
+    options: &Options,
+    release: &Release,
+    commits: &[Commit],
+    prompt: &Prompt,
+) -> anyhow::Result<String> {
+    let mut renderer = new_renderer(args)?;
+    let changelog =
+        aichangelog::generate_document(provider, options, release, commits, prompt).await?;
+    let text = format::render(options.format, &changelog.document);
+    let banner = cost_banner(
+        provider,
+        changelog.usage.prompt_tokens,
+        changelog.usage.completion_tokens,
+    );
+    renderer.next_event()?;
+    renderer.update(&text, &text, &banner)?;
+    renderer.finish(&text, &banner)?;
+    if changelog.repairs > 0 {
+        eprintln!(
+            "{}",
+            format!(
+                "The model needed {} attempts to return a valid changelog",
+                changelog.repairs + 1
+            )
+            .bright_black()
+        );
+    }
+    Ok(text)
+}
+
+/// Asks the user how to revise `changelog` and has the model rewrite it with the whole
+/// conversation so far, until the user is happy with it. Each revision replaces the
+/// previous one on the terminal.
+async fn refine(
+    args: &Args,
+    provider: &dyn Provider,
+    options: &Options,
+    mut prompt: Prompt,
+    mut changelog: String,
+    mut renderer: Renderer,
+) -> anyhow::Result<String> {
+    loop {
+        eprint!("Refine the changelog, or press enter to keep it: ");
+        io::stderr().flush()?;
+        let mut line = String::new();
+        let instruction = match io::stdin().read_line(&mut line)? {
+            0 => return Ok(changelog),
+            _ => line.trim(),
+        };
+        if instruction.is_empty() {
+            return Ok(changelog);
+        }
+
+        prompt.tokens += provider.count_tokens(&changelog)? + provider.count_tokens(instruction)?;
+        prompt.messages.push(Message::assistant(changelog));
+        prompt.messages.push(Message::user(instruction.to_string()));
+        // The banner only shows what the revision costs.
+        prompt.usage = Usage::default();
+
+        renderer.erase(1)?;
+        renderer = new_renderer(args)?;
+        changelog = stream_changelog(&mut renderer, provider, options, &prompt).await?;
+    }
+}
+
+/// Generates `--candidates` changelogs at once, shows them one after another and lets
+/// the user pick one of them or have the model merge them.
+async fn pick_candidate(
+    args: &Args,
+    provider: &dyn Provider,
+    options: &Options,
+    prompt: &Prompt,
+) -> anyhow::Result<String> {
+    eprintln!(
+        "{}",
+        format!("Asking AI for {} candidates", args.candidates).yellow()
+    );
+    let mut candidates =
+        aichangelog::generate_candidates(provider, options, prompt, args.candidates).await?;
+    for (i, text) in candidates.texts.iter().enumerate() {
+        eprintln!(
+            "\n{}\n\n{}",
+            format!("Candidate {}", i + 1).bold(),
+            text.trim()
+        );
+    }
+    eprintln!(
+        "\n{}",
+        cost_banner(
+            provider,
+            candidates.usage.prompt_tokens,
+            candidates.usage.completion_tokens
+        )
+    );
+
+    let count = candidates.texts.len();
+    let choice = loop {
+        eprint!("Pick a candidate (1-{count}) or m to merge them: ");
+        io::stderr().flush()?;
+        let mut line = String::new();
+        if io::stdin().read_line(&mut line)? == 0 {
+            anyhow::bail!("No candidate picked");
+        }
+        let line = line.trim();
+        if line.eq_ignore_ascii_case("m") {
+            break None;
+        }
+        if let Some(i) = line.parse().ok().filter(|i| (1..=count).contains(i)) {
+            break Some(i - 1);
+        }
+    };
+
+    match choice {
+        Some(i) => {
+            let changelog = candidates.texts.swap_remove(i);
+            let banner = format!("{}", format!("Picked candidate {}", i + 1).bright_black());
+            show(args, &changelog, &banner)?;
+            Ok(changelog)
+        }
+        None => {
+            let mut merge = aichangelog::merge_prompt(provider, &candidates.texts)?;
+            merge.usage = candidates.usage;
+            stream_changelog(&mut new_renderer(args)?, provider, options, &merge).await
+        }
+    }
+}
+
+/// A renderer for `args`; with `--refine` and `--edit` everything it shows is a draft.
+fn new_renderer(args: &Args) -> io::Result<Renderer> {
+    Renderer::new(args.plain, args.refine || args.edit)
+}
+
+/// Shows a changelog that is already complete.
+fn show(args: &Args, changelog: &str, banner: &str) -> io::Result<()> {
+    let mut renderer = new_renderer(args)?;
+    renderer.next_event()?;
+    renderer.update(changelog, changelog, banner)?;
+    renderer.finish(changelog, banner)
+}
+
+fn cost_banner(provider: &dyn Provider, prompt_tokens: usize, response_tokens: usize) -> String {
+    let tokens = format!("{}", prompt_tokens + response_tokens).purple();
+    match provider.cost(prompt_tokens, response_tokens) {
+        Some(cost) => format!(
+            "This used {} tokens costing you about {}",
+            tokens,
+            format!("~${cost:0.4}").purple(),
+        ),
+        None => format!("This used {} tokens, {}", tokens, unknown_cost(provider)),
+    }
+}
+
+/// Tells how to configure the price of a model aichangelog has none for.
+fn unknown_cost(provider: &dyn Provider) -> String {
+    format!("cost unknown; set [pricing.\"{}\"]", provider.model())
+}
+
+/// Prints what the library reports, like summarizing progress and retries, to stderr.
+struct Logger;
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Info && metadata.target().starts_with("aichangelog")
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let message = record.args().to_string();
+        if record.level() <= log::Level::Warn {
+            eprintln!("{}", message.yellow());
+        } else {
+            eprintln!("{}", message.bright_black());
+        }
+    }
+
+    fn flush(&self) {}
 }
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     ///Rev range to generate changelog from
+    #[arg(conflicts_with_all = ["latest", "between_tags"])]
     range: Option<String>,
 
+    ///Generate the changelog since the latest tag (<last-tag>..HEAD)
+    #[arg(short, long, conflicts_with = "between_tags")]
+    latest: bool,
+
+    ///Generate one section per consecutive pair of tags
+    #[arg(long)]
+    between_tags: bool,
+
+    ///Only consider tags matching this glob, e.g. 'v*'
+    #[arg(long)]
+    tag_pattern: Option<String>,
+
+    ///Generate one changelog per package: Cargo workspace members and [[packages]] in the config
+    #[arg(short, long, overrides_with = "no_workspace")]
+    workspace: bool,
+
+    ///Generate a single changelog even if the config turns on --workspace
+    #[arg(long, overrides_with = "workspace")]
+    no_workspace: bool,
+
+    ///Only generate the changelog of this package; repeatable, implies --workspace
+    #[arg(long, value_name = "NAME")]
+    package: Vec<String>,
+
+    ///Only include commits touching these paths
+    #[arg(last = true, conflicts_with_all = ["workspace", "package"])]
+    paths: Vec<String>,
+
+    ///Leave out commits whose author ('Name <email>') matches this regex; repeatable
+    #[arg(long, value_name = "REGEX")]
+    exclude_author: Vec<String>,
+
+    ///Leave out commits whose message matches this regex; repeatable
+    #[arg(long, value_name = "REGEX")]
+    exclude_message: Vec<String>,
+
+    ///Leave out merge commits
+    #[arg(long)]
+    no_merges: bool,
+
+    ///Look up referenced issues and pull requests (#123) on GitHub, GitLab or Gitea
+    #[arg(long, overrides_with = "no_enrich")]
+    enrich: bool,
+
+    ///Don't look up referenced issues and pull requests, even if the config turns on --enrich
+    #[arg(long, overrides_with = "enrich")]
+    no_enrich: bool,
+
+    ///Base URL of the forge's REST API; derived from the origin remote by default
+    #[arg(long, value_name = "URL")]
+    forge_url: Option<String>,
+
+    ///Head unreleased changes with the next semantic version, derived from Conventional Commits
+    #[arg(long, overrides_with = "no_bump")]
+    bump: bool,
+
+    ///Head unreleased changes with "Unreleased", even if the config turns on --bump
+    #[arg(long, overrides_with = "bump")]
+    no_bump: bool,
+
+    ///Print the next semantic version and exit; implies --latest
+    #[arg(long, conflicts_with_all = ["range", "between_tags"])]
+    next_version: bool,
+
+    ///Ask the model to double-check the version bump of --bump and --next-version
+    #[arg(long)]
+    check_bump: bool,
+
     ///Only use first line of commit message to reduce tokens
-    #[arg(short, long)]
+    #[arg(short, long, overrides_with = "no_short")]
     short: bool,
 
+    ///Use the full commit messages, even if the config turns on --short
+    #[arg(long, overrides_with = "short")]
+    no_short: bool,
+
+    ///Parse Conventional Commits and group them into sections before asking the AI
+    #[arg(short, long, overrides_with = "no_conventional")]
+    conventional: bool,
+
+    ///Don't group Conventional Commits, even if the config turns on --conventional
+    #[arg(long, overrides_with = "conventional")]
+    no_conventional: bool,
+
     ///Temperature for AI
     /// 0.0 - 2.0
     #[arg(short, long, default_value = "1.0")]
@@ -202,33 +812,217 @@ struct Args {
     ///Model to use
     #[arg(short, long, default_value = "gpt-3.5-turbo")]
     model: openai::Model,
+
+    ///Base URL of an OpenAI-compatible API
+    #[arg(short, long, default_value = openai::DEFAULT_BASE_URL)]
+    base_url: String,
+
+    ///Context size of the model, for models unknown to aichangelog
+    #[arg(long)]
+    context_size: Option<usize>,
+
+    ///How often to retry on rate limits, server and network errors
+    #[arg(long, default_value = "3")]
+    retries: u32,
+
+    ///Ask the model for revisions of the changelog ("shorter", "drop refactors") until you keep it
+    #[arg(long, conflicts_with = "candidates")]
+    refine: bool,
+
+    ///Generate N changelogs at once, then pick one of them or have the model merge them
+    #[arg(long, value_name = "N", default_value = "1", value_parser = clap::value_parser!(u32).range(1..=10))]
+    candidates: u32,
+
+    ///Print the prompt with its token count and worst-case cost instead of sending it
+    #[arg(long)]
+    dry_run: bool,
+
+    ///Always ask the model, even if the changelog is already cached
+    #[arg(long)]
+    no_cache: bool,
+
+    ///Don't redraw the output in place; implied when stdout is not a terminal
+    #[arg(short, long)]
+    plain: bool,
+
+    ///Output format: markdown, keep-a-changelog, json, html or text
+    #[arg(long, default_value = "markdown")]
+    format: Format,
+
+    ///How often to ask the model to fix a reply that doesn't match the schema of --format
+    #[arg(long, value_name = "N", default_value = "2")]
+    repairs: u32,
+
+    ///Edit the changelog in $VISUAL or $EDITOR before writing it to --output or publishing it
+    #[arg(short, long)]
+    edit: bool,
+
+    ///Insert the changelog at the top of this file (e.g. CHANGELOG.md)
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    ///Prompt template file; see the README for the available placeholders
+    #[arg(long)]
+    prompt_file: Option<PathBuf>,
+
+    ///Audience of the changelog, available to prompt templates as {{audience}}
+    #[arg(long, default_value = "developers")]
+    audience: String,
+
+    ///Project name for prompt templates; defaults to the repository's directory name
+    #[arg(long)]
+    project_name: Option<String>,
+
+    #[arg(skip)]
+    prompt: Option<String>,
+
+    #[arg(skip = conventional::default_sections())]
+    sections: Vec<conventional::Section>,
+
+    #[arg(skip)]
+    pricing: HashMap<String, openai::Pricing>,
+
+    #[arg(skip)]
+    filters: FilterConfig,
+
+    #[arg(skip)]
+    packages: Vec<PackageConfig>,
+
+    #[arg(skip)]
+    forge_config: ForgeConfig,
+
+    #[arg(skip)]
+    repo: PathBuf,
 }
 
-#[must_use]
-pub fn count_lines(text: &str, max_width: usize) -> u16 {
-    if text.is_empty() {
-        return 0;
-    }
-    let mut line_count = 0;
-    let mut current_line_width = 0;
-    for cluster in UnicodeSegmentation::graphemes(text, true) {
-        match cluster {
-            "\r" | "\u{FEFF}" => {}
-            "\n" => {
-                line_count += 1;
-                current_line_width = 0;
-            }
-            _ => {
-                current_line_width += 1;
-                if current_line_width > max_width {
-                    line_count += 1;
-                    current_line_width = cluster.chars().count();
+#[derive(Subcommand, Debug)]
+enum Command {
+    ///Generate the changelog of a tag and publish it as a release on GitHub, GitLab or Gitea
+    Publish(PublishArgs),
+
+    ///Manage the cache of generated changelogs
+    Cache {
+        #[command(subcommand)]
+        action: CacheCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum CacheCommand {
+    ///Remove every cached changelog
+    Clear,
+}
+
+#[derive(clap::Args, Debug)]
+struct PublishArgs {
+    ///Tag to publish; the changelog covers the commits since the previous tag
+    tag: String,
+
+    ///Title of the release; defaults to the tag
+    #[arg(long)]
+    name: Option<String>,
+
+    ///Create the release as a draft (GitHub and Gitea)
+    #[arg(long)]
+    draft: bool,
+
+    ///Mark the release as a pre-release (GitHub and Gitea)
+    #[arg(long)]
+    prerelease: bool,
+
+    ///Print the request that would publish the release instead of sending it
+    #[arg(long)]
+    dry_run: bool,
+}
+
+impl Args {
+    /// Fills in everything that wasn't given on the command line from `config`.
+    fn apply(&mut self, matches: &ArgMatches, config: config::Config) {
+        let from_cli = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+        macro_rules! apply {
+            ($($field:ident),*) => {$(
+                if let Some(value) = config.$field {
+                    if !from_cli(stringify!($field)) {
+                        self.$field = value;
+                    }
                 }
-            }
+            )*};
+        }
+        apply!(
+            model,
+            base_url,
+            temp,
+            freq,
+            short,
+            conventional,
+            bump,
+            format,
+            repairs,
+            audience,
+            retries,
+            workspace,
+            enrich
+        );
+        // A flag like `--no-short` wins over a `true` in the config.
+        self.short &= !self.no_short;
+        self.conventional &= !self.no_conventional;
+        self.bump &= !self.no_bump;
+        self.workspace &= !self.no_workspace;
+        self.enrich &= !self.no_enrich;
+        self.workspace |= !self.package.is_empty();
+        self.packages = config.packages.unwrap_or_default();
+        self.forge_config = config.forge.unwrap_or_default();
+        if let Some(url) = self.forge_url.take() {
+            self.forge_config.api_url = Some(url);
+        }
+
+        self.context_size = self.context_size.or(config.context_size);
+        self.tag_pattern = self.tag_pattern.take().or(config.tag_pattern);
+        // `output` from a config file is relative to the root of the work tree, except in
+        // workspace mode, where it is relative to each package.
+        self.output = self
+            .output
+            .take()
+            .or(match (config.output, config.output_dir) {
+                (Some(output), Some(dir)) if !self.workspace => Some(dir.join(output)),
+                (output, _) => output,
+            });
+        self.project_name = self.project_name.take().or(config.project_name);
+        self.prompt_file = self.prompt_file.take().or(config.prompt_file);
+        self.prompt = config.prompt;
+        if let Some(sections) = config.sections {
+            self.sections = sections;
         }
+        self.pricing = config.pricing.unwrap_or_default();
+        self.filters = config.filters.unwrap_or_default().merge(FilterConfig {
+            paths: self.paths.clone(),
+            no_merges: self.no_merges,
+            exclude_authors: self.exclude_author.clone(),
+            exclude_messages: self.exclude_message.clone(),
+        });
     }
 
-    line_count + 1
-}
+    /// The forge to enrich commits from, if `--enrich` is on.
+    fn forge(&self) -> anyhow::Result<Option<Forge>> {
+        if !self.enrich {
+            return Ok(None);
+        }
+        Forge::detect(&self.repo, &self.forge_config).map(Some)
+    }
 
-const SYSTEM_MSG: &str = r#"You are now an AI that takes a range of Git commit messages as input and generates a changelog in the style of update notes using Markdown formatting. The commit messages may be in the format of a one-line summary or a multi-line description."#;
+    fn options(&self, target: &Target) -> Options {
+        let package = target.package.as_ref().map(|p| p.name.clone());
+        Options {
+            short: self.short,
+            conventional: self.conventional,
+            sections: self.sections.clone(),
+            prompt: self.prompt.clone(),
+            audience: self.audience.clone(),
+            project_name: self.project_name.clone().or(package),
+            temp: self.temp,
+            freq: self.freq,
+            format: self.format,
+            repairs: self.repairs,
+        }
+    }
+}
@@ -1,10 +1,16 @@
 #![allow(dead_code)]
 
+use eventsource_stream::Eventsource;
+use futures::{future::BoxFuture, stream, StreamExt};
+use reqwest::{header::RETRY_AFTER, StatusCode};
 use serde::{Deserialize, Serialize};
 use std::fmt;
 use std::str::FromStr;
+use std::time::Duration;
 
-#[derive(Serialize, Deserialize, Debug)]
+use crate::provider::{Chunk, ChunkStream, Provider};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 pub enum Role {
     System,
@@ -12,7 +18,7 @@ pub enum Role {
     Assistant,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Message {
     pub role: Role,
     pub content: String,
@@ -49,7 +55,7 @@ pub struct ErrorRoot {
 #[serde(rename_all = "camelCase")]
 pub struct Error {
     pub message: String,
-    #[serde(rename = "type")]
+    #[serde(rename = "type", default)]
     pub type_field: String,
     pub param: Option<String>,
     pub code: Option<String>,
@@ -57,14 +63,18 @@ pub struct Error {
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "{} ({:?}): {:?}",
-            self.type_field, self.code, self.message
-        )
+        match self.code.as_deref().filter(|code| !code.is_empty()) {
+            Some(code) => write!(f, "{} ({})", self.message, code),
+            None if !self.type_field.is_empty() => {
+                write!(f, "{} ({})", self.message, self.type_field)
+            }
+            None => write!(f, "{}", self.message),
+        }
     }
 }
 
+impl std::error::Error for Error {}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Request {
     pub model: String,
@@ -72,7 +82,17 @@ pub struct Request {
     pub n: i32,
     pub temperature: f64,
     pub frequency_penalty: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<usize>,
     stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<StreamOptions>,
+}
+
+/// Asks the server to send the real token usage in the last chunk of a stream.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StreamOptions {
+    pub include_usage: bool,
 }
 
 impl Request {
@@ -89,9 +109,26 @@ impl Request {
             n,
             temperature,
             frequency_penalty,
+            max_tokens: None,
             stream: true,
+            stream_options: Some(StreamOptions {
+                include_usage: true,
+            }),
         }
     }
+
+    pub fn stream(mut self, stream: bool) -> Self {
+        self.stream = stream;
+        self.stream_options = stream.then_some(StreamOptions {
+            include_usage: true,
+        });
+        self
+    }
+
+    pub const fn max_tokens(mut self, max_tokens: usize) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Default)]
@@ -118,24 +155,283 @@ pub struct Delta {
 }
 
 #[derive(Serialize, Deserialize, Debug)]
+pub struct CompletionResponse {
+    pub id: String,
+    pub object: String,
+    pub created: i64,
+    pub model: String,
+    pub choices: Vec<CompletionChoice>,
+    pub usage: Option<Usage>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CompletionChoice {
+    pub index: i64,
+    pub finish_reason: Option<String>,
+    pub message: Message,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct Usage {
     pub prompt_tokens: usize,
     pub completion_tokens: usize,
     pub total_tokens: usize,
 }
 
+impl Usage {
+    pub fn add(&mut self, other: &Self) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.total_tokens += other.total_tokens;
+    }
+}
+
+pub const DEFAULT_BASE_URL: &str = "https://api.openai.com/v1";
+
+/// Price in USD per 1000 tokens.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct Pricing {
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+impl Pricing {
+    pub fn cost(&self, prompt_tokens: usize, completion_tokens: usize) -> f64 {
+        (prompt_tokens as f64).mul_add(
+            self.prompt / 1000.0,
+            (completion_tokens as f64) * (self.completion / 1000.0),
+        )
+    }
+}
+
+/// [`Provider`] for the OpenAI API or any OpenAI-compatible server.
+pub struct OpenAi {
+    client: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+    model: Model,
+    context_size: Option<usize>,
+    pricing: Option<Pricing>,
+    retries: u32,
+}
+
+/// Upper bound for the exponential backoff between retries.
+const MAX_BACKOFF: Duration = Duration::from_secs(32);
+
+/// Longest `Retry-After` waited for; asking for more gives up instead.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+impl OpenAi {
+    pub fn new(base_url: &str, api_key: Option<String>, model: Model) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key,
+            model,
+            context_size: None,
+            pricing: None,
+            retries: 3,
+        }
+    }
+
+    /// How often to retry on rate limits, server errors and network failures.
+    pub const fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Overrides the context size of the model, e.g. for self-hosted models.
+    pub const fn context_size(mut self, context_size: Option<usize>) -> Self {
+        self.context_size = context_size;
+        self
+    }
+
+    /// Overrides the built-in prices of the model.
+    pub const fn pricing(mut self, pricing: Option<Pricing>) -> Self {
+        self.pricing = pricing;
+        self
+    }
+
+    fn request(&self, req: &Request) -> anyhow::Result<reqwest::RequestBuilder> {
+        let mut builder = self
+            .client
+            .post(format!("{}/chat/completions", self.base_url))
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_string(req)?);
+        if let Some(api_key) = &self.api_key {
+            builder = builder.bearer_auth(api_key);
+        }
+        Ok(builder)
+    }
+
+    /// Sends `req`, retrying with exponential backoff on transient failures.
+    ///
+    /// `Retry-After` is honored when the server sends it, up to [`MAX_RETRY_AFTER`];
+    /// longer waits fail right away. Errors are decoded from the
+    /// JSON error body so the user sees why the request was rejected. `attempt` counts
+    /// the retries so far, so that [`Provider::stream`] can share them.
+    async fn send(&self, req: &Request, attempt: &mut u32) -> anyhow::Result<reqwest::Response> {
+        loop {
+            let (err, retry_after) = match self.request(req)?.send().await {
+                Ok(resp) if resp.status().is_success() => return Ok(resp),
+                Ok(resp) => {
+                    let status = resp.status();
+                    let retry_after = resp
+                        .headers()
+                        .get(RETRY_AFTER)
+                        .and_then(|value| value.to_str().ok())
+                        .and_then(|value| value.trim().parse::<f64>().ok())
+                        .and_then(|secs| Duration::try_from_secs_f64(secs).ok());
+                    let body = resp.text().await.unwrap_or_default();
+                    let err = serde_json::from_str::<ErrorRoot>(&body)
+                        .ok()
+                        .map(|e| e.error);
+                    let retryable = is_retryable(status, err.as_ref());
+                    let err = match err {
+                        Some(err) => err.into(),
+                        None => anyhow::anyhow!("Request failed with status {status}"),
+                    };
+                    if !retryable {
+                        return Err(err);
+                    }
+                    (err, retry_after)
+                }
+                Err(e) if e.is_timeout() || e.is_connect() || e.is_request() => (e.into(), None),
+                Err(e) => return Err(e.into()),
+            };
+
+            if *attempt >= self.retries {
+                return Err(err);
+            }
+            if let Some(delay) = retry_after.filter(|&delay| delay > MAX_RETRY_AFTER) {
+                log::warn!(
+                    "The server asked to retry in {:.0}s, not waiting longer than {}s",
+                    delay.as_secs_f64(),
+                    MAX_RETRY_AFTER.as_secs()
+                );
+                return Err(err);
+            }
+            self.back_off(*attempt, &err, retry_after).await;
+            *attempt += 1;
+        }
+    }
+
+    /// Waits before retry number `attempt + 1`, for the server's `Retry-After` if it sent
+    /// one or an exponential backoff.
+    async fn back_off(&self, attempt: u32, err: &anyhow::Error, retry_after: Option<Duration>) {
+        let delay = retry_after
+            .unwrap_or_else(|| Duration::from_secs(1 << attempt.min(5)).min(MAX_BACKOFF));
+        log::warn!("{err} Retrying in {:.1}s...", delay.as_secs_f64());
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Rate limits and server errors are worth retrying, except for an exhausted quota.
+fn is_retryable(status: StatusCode, err: Option<&Error>) -> bool {
+    if err.and_then(|e| e.code.as_deref()) == Some("insufficient_quota") {
+        return false;
+    }
+    status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::REQUEST_TIMEOUT
+        || status.is_server_error()
+}
+
+impl Provider for OpenAi {
+    fn model(&self) -> String {
+        self.model.to_string()
+    }
+
+    fn complete<'a>(
+        &'a self,
+        req: &'a Request,
+    ) -> BoxFuture<'a, anyhow::Result<CompletionResponse>> {
+        Box::pin(async move {
+            let body = self.send(req, &mut 0).await?.text().await?;
+            Ok(serde_json::from_str(&body)?)
+        })
+    }
+
+    /// A stream that fails before its first chunk is retried like a failed request.
+    /// Once text has arrived the caller may have shown it, so a later error ends the
+    /// stream instead.
+    fn stream<'a>(&'a self, req: &'a Request) -> BoxFuture<'a, anyhow::Result<ChunkStream>> {
+        Box::pin(async move {
+            let mut attempt = 0;
+            loop {
+                let mut chunks = self
+                    .send(req, &mut attempt)
+                    .await?
+                    .bytes_stream()
+                    .eventsource()
+                    .map(|event| event.map(|event| event.data).map_err(anyhow::Error::from))
+                    .take_while(|data| {
+                        futures::future::ready(!matches!(data, Ok(d) if d == "[DONE]"))
+                    })
+                    .flat_map(|data| stream::iter(parse_event(data)))
+                    .boxed();
+                match chunks.next().await {
+                    Some(Err(err)) if attempt < self.retries => {
+                        self.back_off(attempt, &err, None).await;
+                        attempt += 1;
+                    }
+                    first => return Ok(stream::iter(first).chain(chunks).boxed()),
+                }
+            }
+        })
+    }
+
+    fn count_tokens(&self, text: &str) -> anyhow::Result<usize> {
+        count_token(text)
+    }
+
+    fn context_size(&self) -> usize {
+        self.context_size
+            .unwrap_or_else(|| self.model.context_size())
+    }
+
+    fn cost(&self, prompt_tokens: usize, completion_tokens: usize) -> Option<f64> {
+        let pricing = self.pricing.or_else(|| self.model.pricing())?;
+        Some(pricing.cost(prompt_tokens, completion_tokens))
+    }
+}
+
+/// Decodes the `data` of a server-sent event; malformed events are skipped.
+fn parse_event(data: anyhow::Result<String>) -> Vec<anyhow::Result<Chunk>> {
+    let data = match data {
+        Ok(data) => data,
+        Err(e) => return vec![Err(e)],
+    };
+    if let Ok(err) = serde_json::from_str::<ErrorRoot>(&data) {
+        return vec![Err(err.error.into())];
+    }
+    let resp = serde_json::from_str::<Response>(&data).unwrap_or_default();
+    let mut chunks = Vec::new();
+    if let Some(delta) = resp.choices.first().and_then(|c| c.delta.content.clone()) {
+        if !delta.is_empty() {
+            chunks.push(Ok(Chunk::Delta(delta)));
+        }
+    }
+    if let Some(usage) = resp.usage {
+        chunks.push(Ok(Chunk::Usage(usage)));
+    }
+    chunks
+}
+
 pub fn count_token(s: &str) -> anyhow::Result<usize> {
-    let bpe = tiktoken_rs::cl100k_base()?;
-    let tokens = bpe.encode_with_special_tokens(s);
+    let bpe = tiktoken_rs::cl100k_base_singleton();
+    let tokens = bpe.lock().encode_with_special_tokens(s);
     Ok(tokens.len())
 }
 
-#[derive(Debug, Copy, Clone, Default)]
+#[derive(Debug, Clone, Default)]
 pub enum Model {
     #[default]
     Gpt35Turbo,
     Gpt4,
     Gpt432k,
+    /// Any other model, e.g. one served by a self-hosted OpenAI-compatible server.
+    Other(String),
 }
 
 impl FromStr for Model {
@@ -146,17 +442,19 @@ impl FromStr for Model {
             "gpt-3.5-turbo" => Ok(Self::Gpt35Turbo),
             "gpt-4" => Ok(Self::Gpt4),
             "gpt-4-32k" => Ok(Self::Gpt432k),
-            _ => Err(format!("{} is not a valid model", s)),
+            "" => Err(String::from("model name must not be empty")),
+            _ => Ok(Self::Other(s.to_string())),
         }
     }
 }
 
-impl ToString for Model {
-    fn to_string(&self) -> String {
+impl fmt::Display for Model {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::Gpt35Turbo { .. } => String::from("gpt-3.5-turbo"),
-            Self::Gpt4 { .. } => String::from("gpt-4"),
-            Self::Gpt432k { .. } => String::from("gpt-4-32k"),
+            Self::Gpt35Turbo => write!(f, "gpt-3.5-turbo"),
+            Self::Gpt4 => write!(f, "gpt-4"),
+            Self::Gpt432k => write!(f, "gpt-4-32k"),
+            Self::Other(name) => write!(f, "{name}"),
         }
     }
 }
@@ -181,22 +479,31 @@ impl<'de> Deserialize<'de> for Model {
 }
 
 impl Model {
-    pub fn cost(&self, prompt_tokens: usize, completion_tokens: usize) -> f64 {
-        let (prompt_cost, completion_cost) = match self {
-            Self::Gpt35Turbo => (0.002, 0.002),
+    /// Built-in prices, `None` for models aichangelog doesn't know the price of; set
+    /// them with `[pricing."<model>"]` in the config.
+    pub fn pricing(&self) -> Option<Pricing> {
+        let (prompt, completion) = match self {
+            Self::Gpt35Turbo => (0.0005, 0.0015),
             Self::Gpt4 => (0.03, 0.06),
             Self::Gpt432k => (0.06, 0.12),
+            Self::Other(name) => match name.as_str() {
+                "gpt-4-turbo" => (0.01, 0.03),
+                "gpt-4o" => (0.0025, 0.01),
+                "gpt-4o-mini" => (0.00015, 0.0006),
+                _ => return None,
+            },
         };
-        (prompt_tokens as f64).mul_add(
-            prompt_cost / 1000.0,
-            (completion_tokens as f64) * (completion_cost / 1000.0),
-        )
+        Some(Pricing { prompt, completion })
+    }
+    pub fn cost(&self, prompt_tokens: usize, completion_tokens: usize) -> Option<f64> {
+        Some(self.pricing()?.cost(prompt_tokens, completion_tokens))
     }
     pub const fn context_size(&self) -> usize {
         match self {
             Self::Gpt35Turbo => 4096,
             Self::Gpt4 => 8192,
             Self::Gpt432k => 32768,
+            Self::Other(_) => 4096,
         }
     }
 }
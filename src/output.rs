@@ -0,0 +1,126 @@
+use std::{fs, io, path::Path};
+
+const DEFAULT_PREAMBLE: &str = "# Changelog\n";
+
+/// Inserts a new release section at the top of the changelog file at `path`.
+///
+/// The section goes below the title/preamble and above the first previous release
+/// (`## ` heading other than `Unreleased`). A new `Unreleased` section replaces the
+/// existing one instead. If the file does not exist it is created. The file is
+/// replaced atomically so older entries are never lost on failure.
+pub fn insert_release(path: &Path, heading: &str, body: &str) -> anyhow::Result<()> {
+    let existing = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::from(DEFAULT_PREAMBLE),
+        Err(e) => return Err(e.into()),
+    };
+
+    let section = format!("## {}\n\n{}\n", heading, demote_headings(body.trim(), 3));
+    let (start, end) = if is_unreleased(heading) {
+        unreleased_section(&existing).unwrap_or_else(|| {
+            let at = insertion_point(&existing);
+            (at, at)
+        })
+    } else {
+        let at = insertion_point(&existing);
+        (at, at)
+    };
+    let (before, after) = (&existing[..start], &existing[end..]);
+
+    let mut content = String::with_capacity(existing.len() + section.len() + 2);
+    content.push_str(before.trim_end());
+    content.push_str("\n\n");
+    content.push_str(&section);
+    if !after.trim().is_empty() {
+        content.push('\n');
+        content.push_str(after);
+    }
+
+    let tmp = path.with_extension("md.tmp");
+    fs::write(&tmp, content)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Replaces the file at `path` with `content`, for formats that hold a single release.
+pub fn write(path: &Path, content: &str) -> anyhow::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, content)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Byte offset of the first previous release heading, or the end of the file.
+fn insertion_point(content: &str) -> usize {
+    release_headings(content)
+        .into_iter()
+        .find(|&(_, line)| !is_unreleased(line))
+        .map_or(content.len(), |(offset, _)| offset)
+}
+
+/// Byte range of the `Unreleased` section, if the changelog starts with one.
+fn unreleased_section(content: &str) -> Option<(usize, usize)> {
+    let headings = release_headings(content);
+    let &(start, line) = headings.first()?;
+    if !is_unreleased(line) {
+        return None;
+    }
+    let end = headings.get(1).map_or(content.len(), |&(offset, _)| offset);
+    Some((start, end))
+}
+
+/// Byte offsets and lines of the `## ` headings outside code fences.
+fn release_headings(content: &str) -> Vec<(usize, &str)> {
+    let mut headings = Vec::new();
+    let mut offset = 0;
+    let mut in_fence = false;
+    for line in content.split_inclusive('\n') {
+        if line.trim_start().starts_with("```") {
+            in_fence = !in_fence;
+        } else if !in_fence && line.starts_with("## ") {
+            headings.push((offset, line));
+        }
+        offset += line.len();
+    }
+    headings
+}
+
+fn is_unreleased(heading: &str) -> bool {
+    heading.to_lowercase().contains("unreleased")
+}
+
+/// Shifts all Markdown headings in `text` so the highest one has level `top`.
+fn demote_headings(text: &str, top: usize) -> String {
+    let level = |line: &str| {
+        let hashes = line.chars().take_while(|&c| c == '#').count();
+        (hashes > 0 && line[hashes..].starts_with(' ')).then_some(hashes)
+    };
+
+    let mut in_fence = false;
+    let mut min_level = None;
+    for line in text.lines() {
+        if line.trim_start().starts_with("```") {
+            in_fence = !in_fence;
+        } else if let Some(l) = level(line).filter(|_| !in_fence) {
+            min_level = Some(min_level.map_or(l, |m: usize| m.min(l)));
+        }
+    }
+    let Some(min_level) = min_level else {
+        return text.to_string();
+    };
+    let shift = top.saturating_sub(min_level);
+
+    let mut in_fence = false;
+    let mut out = String::with_capacity(text.len() + shift * 8);
+    for line in text.lines() {
+        if line.trim_start().starts_with("```") {
+            in_fence = !in_fence;
+        } else if !in_fence && level(line).is_some() {
+            out.push_str(&"#".repeat(shift));
+        }
+        out.push_str(line);
+        out.push('\n');
+    }
+    out.truncate(out.trim_end().len());
+    out
+}
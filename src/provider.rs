@@ -0,0 +1,42 @@
+use futures::{future::BoxFuture, stream::BoxStream};
+
+use crate::openai::{CompletionResponse, Request, Usage};
+
+/// An event of a streamed changelog.
+#[derive(Debug, Clone)]
+pub enum Chunk {
+    /// The next piece of text.
+    Delta(String),
+    /// What the request was billed, if the server reports it.
+    Usage(Usage),
+}
+
+/// The events of a streaming completion, ending where the reply does. An error the
+/// server reports mid-stream ends it with an `Err`.
+pub type ChunkStream = BoxStream<'static, anyhow::Result<Chunk>>;
+
+/// A chat completion backend.
+///
+/// [`crate::openai::OpenAi`] implements this for the OpenAI API and every server that
+/// speaks the same protocol (llama.cpp, vLLM, Ollama, ...).
+pub trait Provider: Send + Sync {
+    /// Name of the model requests are sent to.
+    fn model(&self) -> String;
+
+    /// Sends a non-streaming chat completion request.
+    fn complete<'a>(
+        &'a self,
+        req: &'a Request,
+    ) -> BoxFuture<'a, anyhow::Result<CompletionResponse>>;
+
+    /// Opens a streaming chat completion request.
+    fn stream<'a>(&'a self, req: &'a Request) -> BoxFuture<'a, anyhow::Result<ChunkStream>>;
+
+    fn count_tokens(&self, text: &str) -> anyhow::Result<usize>;
+
+    /// Maximum number of tokens for prompt and reply combined.
+    fn context_size(&self) -> usize;
+
+    /// Estimated cost in USD, `None` if the price of the model is unknown.
+    fn cost(&self, prompt_tokens: usize, completion_tokens: usize) -> Option<f64>;
+}
@@ -0,0 +1,204 @@
+use std::{
+    io::{self, IsTerminal, Write},
+    time::Duration,
+};
+
+use colored::Colorize;
+use crossterm::{
+    cursor::{self, MoveToColumn, MoveToPreviousLine},
+    execute,
+    style::{Color, Print, ResetColor, SetForegroundColor},
+    terminal::{self, Clear, ClearType},
+};
+use tokio::task::JoinHandle;
+use unicode_segmentation::UnicodeSegmentation;
+
+const SEPARATOR: &str = "=======================";
+
+/// Writes the streamed changelog to stdout.
+///
+/// On a terminal the output is redrawn in place below a spinner. Otherwise (or with
+/// `--plain`) tokens are written straight through and the cost banner goes to stderr,
+/// so redirecting stdout yields clean Markdown. A draft, which the user may still revise
+/// or edit, is written to stderr instead; the caller prints the final version.
+pub struct Renderer {
+    interactive: bool,
+    draft: bool,
+    spinner: Option<JoinHandle<()>>,
+    term_width: usize,
+    lines_to_move_up: u16,
+    /// Lines drawn once finished, counting from the spinner.
+    height: u16,
+}
+
+impl Renderer {
+    pub fn new(plain: bool, draft: bool) -> io::Result<Self> {
+        let interactive = is_interactive(plain);
+        let term_width = if interactive {
+            terminal::size()?.0 as usize
+        } else {
+            0
+        };
+        Ok(Self {
+            interactive,
+            draft,
+            spinner: interactive.then(|| tokio::spawn(spinner())),
+            term_width,
+            lines_to_move_up: 0,
+            height: 0,
+        })
+    }
+
+    /// Prepares the terminal for the next event of the stream.
+    pub fn next_event(&mut self) -> io::Result<()> {
+        if !self.interactive {
+            return Ok(());
+        }
+        let mut stdout = io::stdout();
+        if let Some(spinner) = self.spinner.take() {
+            spinner.abort();
+            execute!(stdout, Clear(ClearType::CurrentLine), MoveToColumn(0))?;
+            print!("\n\n");
+        }
+        execute!(
+            stdout,
+            cursor::SavePosition,
+            MoveToPreviousLine(self.lines_to_move_up),
+        )?;
+        self.lines_to_move_up = 0;
+        Ok(())
+    }
+
+    /// Shows the changelog after `delta` has been appended to it.
+    pub fn update(&mut self, changelog: &str, delta: &str, banner: &str) -> io::Result<()> {
+        if !self.interactive {
+            return self.write(delta);
+        }
+        let mut stdout = io::stdout();
+        execute!(stdout, Clear(ClearType::FromCursorDown))?;
+        let outp = format!(
+            "{}{}\n\n{}\n",
+            Print(format!("{}\n", SEPARATOR).bright_black()),
+            banner,
+            changelog,
+        );
+        print!("{outp}");
+        self.lines_to_move_up += count_lines(&outp, self.term_width) - 1;
+        Ok(())
+    }
+
+    pub fn finish(&mut self, changelog: &str, banner: &str) -> io::Result<()> {
+        if !self.interactive {
+            if !changelog.ends_with('\n') {
+                self.write("\n")?;
+            }
+            eprintln!("{banner}");
+            return Ok(());
+        }
+        // Two blank lines below the spinner, the output and the closing separator.
+        self.height = 2 + self.lines_to_move_up + 1;
+        execute!(
+            io::stdout(),
+            cursor::RestorePosition,
+            Print(format!("{}\n", SEPARATOR).bright_black()),
+        )
+    }
+
+    /// Removes the finished output from the terminal, along with `extra_lines` printed
+    /// below it since, so the next one takes its place.
+    pub fn erase(self, extra_lines: u16) -> io::Result<()> {
+        if !self.interactive {
+            return Ok(());
+        }
+        execute!(
+            io::stdout(),
+            MoveToPreviousLine(self.height + extra_lines),
+            Clear(ClearType::FromCursorDown),
+        )
+    }
+
+    /// Writes `text` straight through, to stderr for a draft.
+    fn write(&self, text: &str) -> io::Result<()> {
+        if self.draft {
+            let mut stderr = io::stderr();
+            stderr.write_all(text.as_bytes())?;
+            stderr.flush()
+        } else {
+            let mut stdout = io::stdout();
+            stdout.write_all(text.as_bytes())?;
+            stdout.flush()
+        }
+    }
+}
+
+/// Whether output is redrawn in place rather than written straight through.
+pub fn is_interactive(plain: bool) -> bool {
+    !plain && io::stdout().is_terminal()
+}
+
+impl Drop for Renderer {
+    fn drop(&mut self) {
+        if let Some(spinner) = self.spinner.take() {
+            spinner.abort();
+        }
+    }
+}
+
+async fn spinner() {
+    let emoji_support =
+        terminal_supports_emoji::supports_emoji(terminal_supports_emoji::Stream::Stdout);
+    let frames = if emoji_support {
+        vec![
+            "🕛", "🕐", "🕑", "🕒", "🕓", "🕔", "🕕", "🕖", "🕗", "🕘", "🕙", "🕚",
+        ]
+    } else {
+        vec!["/", "-", "\\", "|"]
+    };
+    let mut current_frame = 0;
+    let mut stdout = io::stdout();
+    loop {
+        current_frame = (current_frame + 1) % frames.len();
+        match execute!(
+            stdout,
+            Clear(ClearType::CurrentLine),
+            MoveToColumn(0),
+            SetForegroundColor(Color::Yellow),
+            Print("Asking AI "),
+            Print(frames[current_frame]),
+            ResetColor
+        ) {
+            Ok(_) => {}
+            Err(_) => {
+                break;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(150)).await;
+    }
+}
+
+#[must_use]
+pub fn count_lines(text: &str, max_width: usize) -> u16 {
+    if text.is_empty() {
+        return 0;
+    }
+    let mut line_count = 0;
+    let mut current_line_width = 0;
+    for cluster in UnicodeSegmentation::graphemes(text, true) {
+        match cluster {
+            "\r" | "\u{FEFF}" => {}
+            "\n" => {
+                line_count += 1;
+                current_line_width = 0;
+            }
+            _ => {
+                current_line_width += 1;
+                if current_line_width > max_width {
+                    line_count += 1;
+                    current_line_width = cluster.chars().count();
+                }
+            }
+        }
+    }
+
+    line_count + 1
+}
@@ -0,0 +1,170 @@
+use std::fmt;
+
+use crate::{
+    conventional::ConventionalCommit,
+    git::Commit,
+    openai::{Message, Request},
+    provider::Provider,
+};
+
+/// Which part of the version a release increments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Bump {
+    Patch,
+    Minor,
+    Major,
+}
+
+impl Bump {
+    /// Major for breaking changes, minor for features, patch for everything else.
+    pub fn of(commits: &[Commit]) -> Self {
+        commits
+            .iter()
+            .filter_map(|commit| ConventionalCommit::parse(&commit.message()))
+            .map(|cc| match () {
+                () if cc.breaking => Self::Major,
+                () if cc.kind == "feat" => Self::Minor,
+                () => Self::Patch,
+            })
+            .max()
+            .unwrap_or(Self::Patch)
+    }
+
+    /// Finds `major`, `minor` or `patch` in a reply of the model.
+    pub fn parse(text: &str) -> Option<Self> {
+        let text = text.to_lowercase();
+        [
+            ("major", Self::Major),
+            ("minor", Self::Minor),
+            ("patch", Self::Patch),
+        ]
+        .into_iter()
+        .filter_map(|(word, bump)| text.find(word).map(|i| (i, bump)))
+        .min()
+        .map(|(_, bump)| bump)
+    }
+}
+
+impl fmt::Display for Bump {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Patch => "patch",
+            Self::Minor => "minor",
+            Self::Major => "major",
+        })
+    }
+}
+
+/// A `MAJOR.MINOR.PATCH` version, keeping whatever the tag puts in front of it, like `v`
+/// or `foo-v`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+    pub prefix: String,
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+}
+
+impl Version {
+    /// Parses a tag like `v1.2.3` or `foo-v1.2.3`. Pre-release and build metadata are
+    /// dropped.
+    pub fn parse(tag: &str) -> Option<Self> {
+        tag.char_indices()
+            .filter(|&(i, c)| {
+                c.is_ascii_digit() && !tag[..i].ends_with(|p: char| p.is_ascii_digit() || p == '.')
+            })
+            .find_map(|(i, _)| {
+                let core = tag[i..].split(['-', '+']).next()?;
+                let numbers: Vec<u64> = core
+                    .split('.')
+                    .map(|n| n.parse().ok())
+                    .collect::<Option<_>>()?;
+                let [major, minor, patch] = numbers[..] else {
+                    return None;
+                };
+                Some(Self {
+                    prefix: tag[..i].to_string(),
+                    major,
+                    minor,
+                    patch,
+                })
+            })
+    }
+
+    pub fn bump(&self, bump: Bump) -> Self {
+        let (major, minor, patch) = match bump {
+            Bump::Major => (self.major + 1, 0, 0),
+            Bump::Minor => (self.major, self.minor + 1, 0),
+            Bump::Patch => (self.major, self.minor, self.patch + 1),
+        };
+        Self {
+            prefix: self.prefix.clone(),
+            major,
+            minor,
+            patch,
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}{}.{}.{}",
+            self.prefix, self.major, self.minor, self.patch
+        )
+    }
+}
+
+/// The version following `base`, the tag of the previous release, by `bump`.
+///
+/// Without a previous release the count starts at `0.0.0`, with the prefix taken from
+/// `tag_pattern` (`foo-v*` gives `foo-v`) or `v`.
+pub fn next_version(
+    base: Option<&str>,
+    tag_pattern: Option<&str>,
+    bump: Bump,
+) -> anyhow::Result<Version> {
+    let base = match base {
+        Some(tag) => match Version::parse(tag) {
+            Some(version) => version,
+            None => anyhow::bail!("Tag {tag} is not a semantic version"),
+        },
+        None => Version {
+            prefix: tag_pattern
+                .and_then(|p| p.strip_suffix('*'))
+                .filter(|p| !p.contains(['*', '?', '[']))
+                .unwrap_or("v")
+                .to_string(),
+            major: 0,
+            minor: 0,
+            patch: 0,
+        },
+    };
+    Ok(base.bump(bump))
+}
+
+/// Asks the model which bump `commits` deserve, as a second opinion on [`Bump::of`].
+pub async fn ask_model(
+    provider: &dyn Provider,
+    commits: &[Commit],
+    temp: f64,
+) -> anyhow::Result<Option<Bump>> {
+    let log: String = commits.iter().map(|c| c.compact(true)).collect();
+    let req = Request::new(
+        provider.model(),
+        vec![Message::system(String::from(BUMP_MSG)), Message::user(log)],
+        1,
+        temp,
+        0.0,
+    )
+    .stream(false)
+    .max_tokens(16);
+    let resp = provider.complete(&req).await?;
+    Ok(resp
+        .choices
+        .first()
+        .and_then(|choice| Bump::parse(&choice.message.content)))
+}
+
+const BUMP_MSG: &str = r#"You are now an AI that takes a range of Git commit messages as input and decides how the semantic version of the project has to change for a release containing them: "major" if any change breaks compatibility, "minor" if new features were added, "patch" otherwise. Reply with exactly one word: major, minor or patch."#;
@@ -0,0 +1,203 @@
+use crate::{
+    openai::{self, Message, Usage},
+    provider::Provider,
+};
+
+/// Tokens kept free in the context for the model's reply.
+pub const RESPONSE_RESERVE: usize = 1024;
+
+/// Summarizing rounds after which [`reduce`] gives up.
+const MAX_ROUNDS: usize = 5;
+
+/// Rough per-request overhead of the chat format (role markers, separators).
+const MESSAGE_OVERHEAD: usize = 16;
+
+/// How many tokens of user content fit into a request with the given system message.
+pub fn budget(provider: &dyn Provider, system_msg: &str) -> anyhow::Result<usize> {
+    let used = provider.count_tokens(system_msg)? + RESPONSE_RESERVE + MESSAGE_OVERHEAD;
+    provider.context_size().checked_sub(used).ok_or_else(|| {
+        anyhow::anyhow!(
+            "The context of {} is too small to fit the system message and a reply",
+            provider.model()
+        )
+    })
+}
+
+/// Splits a git log into chunks of at most `budget` tokens.
+///
+/// Chunks are cut at entry boundaries (see [`entries`]), falling back to line
+/// boundaries for single entries that are too large.
+pub fn split(provider: &dyn Provider, log: &str, budget: usize) -> anyhow::Result<Vec<String>> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    let mut current_tokens = 0;
+
+    for entry in entries(log) {
+        let tokens = provider.count_tokens(entry)?;
+        if tokens > budget {
+            for line in entry.split_inclusive('\n') {
+                let tokens = provider.count_tokens(line)?;
+                push(
+                    &mut chunks,
+                    &mut current,
+                    &mut current_tokens,
+                    line,
+                    tokens,
+                    budget,
+                );
+            }
+        } else {
+            push(
+                &mut chunks,
+                &mut current,
+                &mut current_tokens,
+                entry,
+                tokens,
+                budget,
+            );
+        }
+    }
+    if !current.trim().is_empty() {
+        chunks.push(current);
+    }
+
+    Ok(chunks)
+}
+
+fn push(
+    chunks: &mut Vec<String>,
+    current: &mut String,
+    current_tokens: &mut usize,
+    text: &str,
+    tokens: usize,
+    budget: usize,
+) {
+    if *current_tokens + tokens > budget && !current.trim().is_empty() {
+        chunks.push(std::mem::take(current));
+        *current_tokens = 0;
+    }
+    current.push_str(text);
+    *current_tokens += tokens;
+}
+
+/// Splits the text into entries, each starting at an unindented line.
+///
+/// Commits, grouped entries and summary list items all start at column 0 with their
+/// details indented below, so this keeps every commit together.
+fn entries(log: &str) -> Vec<&str> {
+    let mut starts = vec![0];
+    let mut offset = 0;
+    for line in log.split_inclusive('\n') {
+        if offset != 0 && !line.trim().is_empty() && !line.starts_with(char::is_whitespace) {
+            starts.push(offset);
+        }
+        offset += line.len();
+    }
+    starts.push(log.len());
+    starts.windows(2).map(|w| &log[w[0]..w[1]]).collect()
+}
+
+/// Result of the map phase: the text to send in the final request and the usage it took.
+pub struct Reduced {
+    pub content: String,
+    pub summarized: bool,
+    pub usage: Usage,
+}
+
+/// Summarizes `log` chunk by chunk until it fits the final request with `system_msg`.
+///
+/// Each round splits the input into token-bounded chunks and summarizes every chunk on
+/// its own. The summaries are joined and the process repeats until the result fits.
+/// Summaries are capped at a third of a chunk, and a round that doesn't shrink the text
+/// or too many rounds end the process with an error rather than billing forever.
+pub async fn reduce(
+    log: String,
+    system_msg: &str,
+    provider: &dyn Provider,
+    temp: f64,
+    freq: f64,
+) -> anyhow::Result<Reduced> {
+    let final_budget = budget(provider, system_msg)?.min(budget(provider, MERGE_MSG)?);
+    let chunk_budget = budget(provider, CHUNK_MSG)?;
+    let summary_tokens = (chunk_budget / 3).clamp(1, RESPONSE_RESERVE);
+
+    let mut reduced = Reduced {
+        content: log,
+        summarized: false,
+        usage: Usage::default(),
+    };
+    if provider.count_tokens(&reduced.content)? <= budget(provider, system_msg)? {
+        return Ok(reduced);
+    }
+
+    let mut tokens = provider.count_tokens(&reduced.content)?;
+    let mut rounds = 0;
+    while tokens > final_budget {
+        if rounds == MAX_ROUNDS {
+            anyhow::bail!(
+                "The git log still doesn't fit the context of {} after {} rounds of summarizing",
+                provider.model(),
+                MAX_ROUNDS
+            );
+        }
+        rounds += 1;
+        let chunks = split(provider, &reduced.content, chunk_budget)?;
+        let mut summaries = Vec::with_capacity(chunks.len());
+        for (i, chunk) in chunks.into_iter().enumerate() {
+            log::info!("Summarizing part {} of the git log...", i + 1);
+            let req = openai::Request::new(
+                provider.model(),
+                vec![
+                    Message::system(String::from(CHUNK_MSG)),
+                    Message::user(chunk),
+                ],
+                1,
+                temp,
+                freq,
+            )
+            .stream(false)
+            .max_tokens(summary_tokens);
+            let resp = provider.complete(&req).await?;
+            let summary = resp
+                .choices
+                .into_iter()
+                .next()
+                .map(|c| c.message.content)
+                .unwrap_or_default();
+            let usage = match resp.usage {
+                Some(usage) => usage,
+                None => {
+                    let prompt_tokens = req
+                        .messages
+                        .iter()
+                        .map(|m| provider.count_tokens(&m.content))
+                        .sum::<anyhow::Result<usize>>()?;
+                    let completion_tokens = provider.count_tokens(&summary)?;
+                    Usage {
+                        prompt_tokens,
+                        completion_tokens,
+                        total_tokens: prompt_tokens + completion_tokens,
+                    }
+                }
+            };
+            reduced.usage.add(&usage);
+            summaries.push(summary.trim().to_string());
+        }
+        reduced.content = summaries.join("\n\n");
+        reduced.summarized = true;
+
+        let summarized_tokens = provider.count_tokens(&reduced.content)?;
+        if summarized_tokens >= tokens {
+            anyhow::bail!(
+                "Summarizing the git log made it longer ({tokens} to {summarized_tokens} tokens); try a model with a larger context"
+            );
+        }
+        tokens = summarized_tokens;
+    }
+
+    Ok(reduced)
+}
+
+pub const CHUNK_MSG: &str = r#"You are now an AI that takes a part of a range of Git commit messages, or partial changelogs, as input and summarizes the changes as a concise Markdown list. Keep every user-facing change and the section headings of the input; your summary will later be merged with the summaries of the other parts into a single changelog."#;
+
+pub const MERGE_MSG: &str = r#"You are now an AI that takes several partial changelogs, each summarizing a part of the same range of Git commits, and merges them into a single changelog in the style of update notes using Markdown formatting. Remove duplicates, merge sections with the same heading and group related changes."#;
@@ -0,0 +1,42 @@
+/// Renders `{{name}}` placeholders in `template` with the values in `vars`.
+///
+/// Whitespace inside the braces is ignored. Unknown placeholders are an error so typos
+/// don't silently end up in the prompt.
+pub fn render(template: &str, vars: &[(&str, &str)]) -> anyhow::Result<String> {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(len) = rest[start + 2..].find("}}") else {
+            break;
+        };
+        let name = rest[start + 2..start + 2 + len].trim();
+        let Some((_, value)) = vars.iter().find(|(n, _)| *n == name) else {
+            let known: Vec<&str> = vars.iter().map(|(n, _)| *n).collect();
+            anyhow::bail!(
+                "Unknown placeholder {{{{{}}}}} in prompt template, expected one of: {}",
+                name,
+                known.join(", ")
+            );
+        };
+        out.push_str(&rest[..start]);
+        out.push_str(value);
+        rest = &rest[start + 2 + len + 2..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Whether `template` contains the placeholder `name`.
+pub fn uses(template: &str, name: &str) -> bool {
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(len) = rest[start + 2..].find("}}") else {
+            return false;
+        };
+        if rest[start + 2..start + 2 + len].trim() == name {
+            return true;
+        }
+        rest = &rest[start + 2 + len + 2..];
+    }
+    false
+}
@@ -0,0 +1,208 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::{filter::Filter, git};
+
+/// A package of a monorepo that gets its own changelog.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Package {
+    pub name: String,
+    /// Directory of the package, relative to the root of the repository.
+    pub path: PathBuf,
+    /// Glob matching the package's release tags, e.g. `foo-v*`.
+    pub tag_pattern: String,
+}
+
+impl Package {
+    /// `filter`, narrowed down to the commits touching this package.
+    pub fn filter(&self, filter: &Filter) -> Filter {
+        Filter {
+            paths: vec![format!(":(top){}", self.path.display())],
+            ..filter.clone()
+        }
+    }
+}
+
+/// A package listed in the `[[packages]]` tables of the config file.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PackageConfig {
+    pub path: PathBuf,
+    /// Defaults to the name of the directory.
+    pub name: Option<String>,
+    /// Defaults to `<name>-v*`.
+    pub tag_pattern: Option<String>,
+}
+
+/// Finds the packages of `repo`: the members of a Cargo workspace at its root plus the
+/// `configured` ones, sorted by path.
+pub fn packages(repo: &Path, configured: &[PackageConfig]) -> anyhow::Result<Vec<Package>> {
+    let root = git::toplevel(repo)?;
+    let mut packages = cargo_members(&root)?;
+    for config in configured {
+        let name = match &config.name {
+            Some(name) => name.clone(),
+            None => dir_name(&config.path),
+        };
+        let package = Package {
+            tag_pattern: config
+                .tag_pattern
+                .clone()
+                .unwrap_or_else(|| default_tag_pattern(&name)),
+            name,
+            path: normalize(&config.path),
+        };
+        match packages.iter_mut().find(|p| p.path == package.path) {
+            Some(existing) => *existing = package,
+            None => packages.push(package),
+        }
+    }
+    if packages.is_empty() {
+        anyhow::bail!(
+            "No packages found; add a Cargo workspace or [[packages]] to the config file"
+        );
+    }
+    packages.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(packages)
+}
+
+fn default_tag_pattern(name: &str) -> String {
+    format!("{name}-v*")
+}
+
+/// Members of the Cargo workspace whose manifest is at `root`, if there is one.
+fn cargo_members(root: &Path) -> anyhow::Result<Vec<Package>> {
+    let Some(manifest) = read_manifest(&root.join("Cargo.toml"))? else {
+        return Ok(Vec::new());
+    };
+    let Some(workspace) = manifest.get("workspace") else {
+        return Ok(Vec::new());
+    };
+    let patterns = |key: &str| -> Vec<String> {
+        workspace
+            .get(key)
+            .and_then(toml::Value::as_array)
+            .map(|values| {
+                values
+                    .iter()
+                    .filter_map(toml::Value::as_str)
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+
+    let excluded: Vec<PathBuf> = patterns("exclude")
+        .iter()
+        .flat_map(|pattern| expand(root, pattern))
+        .collect();
+    let mut members = Vec::new();
+    for path in patterns("members")
+        .iter()
+        .flat_map(|pattern| expand(root, pattern))
+    {
+        if excluded.contains(&path) || members.iter().any(|p: &Package| p.path == path) {
+            continue;
+        }
+        let Some(member) = read_manifest(&root.join(&path).join("Cargo.toml"))? else {
+            continue;
+        };
+        let name = member
+            .get("package")
+            .and_then(|package| package.get("name"))
+            .and_then(toml::Value::as_str)
+            .map_or_else(|| dir_name(&path), String::from);
+        members.push(Package {
+            tag_pattern: default_tag_pattern(&name),
+            name,
+            path,
+        });
+    }
+    Ok(members)
+}
+
+fn read_manifest(path: &Path) -> anyhow::Result<Option<toml::Table>> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(Some(toml::from_str(&content).map_err(|e| {
+            anyhow::anyhow!("Invalid manifest {}: {}", path.display(), e)
+        })?)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Directories below `root` matching `pattern`, which may use `*` and `?` in any of
+/// its components, as paths relative to `root`.
+fn expand(root: &Path, pattern: &str) -> Vec<PathBuf> {
+    let mut paths = vec![PathBuf::new()];
+    for component in normalize(Path::new(pattern)).iter() {
+        let component = component.to_string_lossy();
+        if !component.contains(['*', '?']) {
+            paths.iter_mut().for_each(|path| path.push(&*component));
+            continue;
+        }
+        paths = paths
+            .iter()
+            .flat_map(|path| {
+                let mut matches: Vec<PathBuf> = fs::read_dir(root.join(path))
+                    .into_iter()
+                    .flatten()
+                    .flatten()
+                    .filter(|entry| entry.path().is_dir())
+                    .filter(|entry| wildcard(&component, &entry.file_name().to_string_lossy()))
+                    .map(|entry| path.join(entry.file_name()))
+                    .collect();
+                matches.sort();
+                matches
+            })
+            .collect();
+    }
+    paths.retain(|path| root.join(path).is_dir());
+    paths
+}
+
+/// Matches `name` against a pattern where `*` stands for any run of characters and `?`
+/// for a single one.
+fn wildcard(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((bp, bn)) => {
+                    backtrack = Some((bp, bn + 1));
+                    p = bp + 1;
+                    n = bn + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Drops `.` components and trailing slashes so paths compare equal.
+fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| !matches!(c, std::path::Component::CurDir))
+        .collect()
+}
+
+fn dir_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.display().to_string())
+}
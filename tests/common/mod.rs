@@ -0,0 +1,398 @@
+#![allow(dead_code)]
+
+use std::{
+    collections::VecDeque,
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::PathBuf,
+    process::{Command, Output, Stdio},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use serde_json::{json, Value};
+
+/// A canned reply of the [`MockServer`].
+pub enum Reply {
+    /// `200 text/event-stream` with one `data:` line per entry.
+    Stream(Vec<String>),
+    /// A plain JSON response.
+    Json {
+        status: u16,
+        headers: Vec<(String, String)>,
+        body: String,
+    },
+}
+
+impl Reply {
+    /// A stream that sends `deltas` as chat completion chunks followed by `[DONE]`.
+    pub fn deltas(deltas: &[&str]) -> Self {
+        let mut data: Vec<String> = deltas.iter().map(|delta| chunk(delta)).collect();
+        data.push(String::from("[DONE]"));
+        Self::Stream(data)
+    }
+
+    /// A non-streaming chat completion with `content`.
+    pub fn completion(content: &str) -> Self {
+        Self::Json {
+            status: 200,
+            headers: Vec::new(),
+            body: json!({
+                "id": "chatcmpl-test",
+                "object": "chat.completion",
+                "created": 0,
+                "model": "gpt-3.5-turbo",
+                "choices": [{
+                    "index": 0,
+                    "finish_reason": "stop",
+                    "message": { "role": "assistant", "content": content },
+                }],
+                "usage": { "prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15 },
+            })
+            .to_string(),
+        }
+    }
+
+    /// A non-streaming chat completion with one choice per entry of `contents`.
+    pub fn completions(contents: &[&str]) -> Self {
+        let choices: Vec<Value> = contents
+            .iter()
+            .enumerate()
+            .map(|(index, content)| {
+                json!({
+                    "index": index,
+                    "finish_reason": "stop",
+                    "message": { "role": "assistant", "content": content },
+                })
+            })
+            .collect();
+        Self::json(json!({
+            "id": "chatcmpl-test",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "gpt-3.5-turbo",
+            "choices": choices,
+            "usage": { "prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15 },
+        }))
+    }
+
+    /// A `200` JSON response with `body`.
+    pub fn json(body: Value) -> Self {
+        Self::Json {
+            status: 200,
+            headers: Vec::new(),
+            body: body.to_string(),
+        }
+    }
+
+    /// An OpenAI error body.
+    pub fn error(status: u16, message: &str, code: &str) -> Self {
+        Self::Json {
+            status,
+            headers: Vec::new(),
+            body: error_body(message, code),
+        }
+    }
+}
+
+/// The `data` of a streamed chat completion chunk carrying `delta`.
+pub fn chunk(delta: &str) -> String {
+    json!({
+        "id": "chatcmpl-test",
+        "object": "chat.completion.chunk",
+        "created": 0,
+        "model": "gpt-3.5-turbo",
+        "choices": [{ "index": 0, "finish_reason": null, "delta": { "content": delta } }],
+    })
+    .to_string()
+}
+
+pub fn error_body(message: &str, code: &str) -> String {
+    json!({
+        "error": { "message": message, "type": "invalid_request_error", "param": null, "code": code },
+    })
+    .to_string()
+}
+
+/// A local HTTP server speaking the chat completions protocol.
+///
+/// Every request's JSON body is recorded for assertions and answered with a [`Reply`],
+/// either from a queue or built by a handler. Requests without a body, like the forge
+/// lookups, are recorded as `{"method": ..., "path": ...}`.
+pub struct MockServer {
+    url: String,
+    requests: Arc<Mutex<Vec<Value>>>,
+    routes: Arc<Mutex<Vec<String>>>,
+}
+
+impl MockServer {
+    /// Answers the requests with `replies`, in order.
+    pub fn start(replies: Vec<Reply>) -> Self {
+        let replies = Mutex::new(VecDeque::from(replies));
+        Self::with_handler(move |_| {
+            replies
+                .lock()
+                .unwrap()
+                .pop_front()
+                .unwrap_or_else(|| Reply::error(500, "no reply queued", "mock"))
+        })
+    }
+
+    /// Answers every request with the reply `handler` builds from its body.
+    pub fn with_handler(handler: impl Fn(&Value) -> Reply + Send + 'static) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind mock server");
+        let url = format!("http://{}/v1", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let routes = Arc::new(Mutex::new(Vec::new()));
+
+        let (recorded, recorded_routes) = (Arc::clone(&requests), Arc::clone(&routes));
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else { break };
+                let Some((route, body)) = read_request(&stream) else {
+                    continue;
+                };
+                let reply = handler(&body);
+                recorded.lock().unwrap().push(body);
+                recorded_routes.lock().unwrap().push(route);
+                write_reply(stream, reply);
+            }
+        });
+
+        Self {
+            url,
+            requests,
+            routes,
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn requests(&self) -> Vec<Value> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Method and path of every request, like `POST /v1/chat/completions`.
+    pub fn routes(&self) -> Vec<String> {
+        self.routes.lock().unwrap().clone()
+    }
+}
+
+fn read_request(stream: &TcpStream) -> Option<(String, Value)> {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).ok()?;
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next()?, parts.next()?);
+    let route = format!("{method} {path}");
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().ok()?;
+            }
+        }
+    }
+    if content_length == 0 {
+        return Some((route, json!({ "method": method, "path": path })));
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).ok()?;
+    Some((route, serde_json::from_slice(&body).ok()?))
+}
+
+fn write_reply(mut stream: TcpStream, reply: Reply) {
+    let response = match reply {
+        Reply::Stream(data) => {
+            let events: String = data.iter().map(|d| format!("data: {d}\n\n")).collect();
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nConnection: close\r\n\r\n{events}"
+            )
+        }
+        Reply::Json {
+            status,
+            headers,
+            body,
+        } => {
+            let headers: String = headers
+                .iter()
+                .map(|(name, value)| format!("{name}: {value}\r\n"))
+                .collect();
+            format!(
+                "HTTP/1.1 {status} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{headers}Connection: close\r\n\r\n{body}",
+                body.len()
+            )
+        }
+    };
+    let _ = stream.write_all(response.as_bytes());
+}
+
+/// A throwaway git repository in the system's temp directory.
+pub struct TestRepo {
+    /// Holds the work tree in `repo/` and a fake home directory in `home/`.
+    dir: PathBuf,
+}
+
+impl TestRepo {
+    pub fn new() -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "aichangelog-test-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("home")).unwrap();
+        std::fs::create_dir_all(dir.join("repo")).unwrap();
+        let repo = Self { dir };
+        repo.git(&["init", "-q", "-b", "main"]);
+        repo
+    }
+
+    pub fn path(&self) -> PathBuf {
+        self.dir.join("repo")
+    }
+
+    pub fn git(&self, args: &[&str]) -> String {
+        let output = Command::new("git")
+            .args(args)
+            .current_dir(self.path())
+            .env("HOME", self.dir.join("home"))
+            .env("GIT_CONFIG_NOSYSTEM", "1")
+            .env("GIT_AUTHOR_NAME", "Jane Doe")
+            .env("GIT_AUTHOR_EMAIL", "jane@example.com")
+            .env("GIT_COMMITTER_NAME", "Jane Doe")
+            .env("GIT_COMMITTER_EMAIL", "jane@example.com")
+            .output()
+            .expect("run git");
+        assert!(
+            output.status.success(),
+            "git {:?} failed: {}",
+            args,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    /// Commits a change to `file` with `message`.
+    pub fn commit(&self, file: &str, message: &str) {
+        let path = self.path().join(file);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).unwrap();
+        }
+        let mut content = std::fs::read_to_string(&path).unwrap_or_default();
+        content.push_str(message);
+        content.push('\n');
+        std::fs::write(&path, content).unwrap();
+        self.git(&["add", file]);
+        self.git(&["commit", "-q", "-m", message]);
+    }
+
+    pub fn tag(&self, name: &str) {
+        self.git(&["tag", name]);
+    }
+
+    pub fn write(&self, file: &str, content: &str) {
+        std::fs::write(self.path().join(file), content).unwrap();
+    }
+
+    /// Writes `~/.config/aichangelog/config.toml` of the fake home directory.
+    pub fn write_global_config(&self, content: &str) {
+        let dir = self.dir.join("home").join(".config").join("aichangelog");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("config.toml"), content).unwrap();
+    }
+
+    pub fn read(&self, file: &str) -> String {
+        std::fs::read_to_string(self.path().join(file)).unwrap()
+    }
+
+    /// Runs aichangelog in the repository against `server`.
+    pub fn run(&self, server: &MockServer, args: &[&str]) -> Output {
+        self.run_with_env(server, args, &[])
+    }
+
+    /// Like [`TestRepo::run`], with additional environment variables.
+    pub fn run_with_env(&self, server: &MockServer, args: &[&str], env: &[(&str, &str)]) -> Output {
+        self.command(server, args)
+            .envs(env.iter().copied())
+            .output()
+            .expect("run aichangelog")
+    }
+
+    /// Like [`TestRepo::run`], from the subdirectory `dir` of the repository.
+    pub fn run_in(&self, server: &MockServer, dir: &str, args: &[&str]) -> Output {
+        self.command(server, args)
+            .current_dir(self.path().join(dir))
+            .output()
+            .expect("run aichangelog")
+    }
+
+    /// Like [`TestRepo::run`], typing `input` into aichangelog's stdin.
+    pub fn run_with_input(&self, server: &MockServer, args: &[&str], input: &str) -> Output {
+        let mut child = self
+            .command(server, args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("run aichangelog");
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(input.as_bytes())
+            .unwrap();
+        child.wait_with_output().expect("run aichangelog")
+    }
+
+    fn command(&self, server: &MockServer, args: &[&str]) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_aichangelog"));
+        command
+            .args(["--base-url", server.url(), "--retries", "1"])
+            .args(args)
+            .current_dir(self.path())
+            .env("OPENAI_API_KEY", "sk-test")
+            .env("HOME", self.dir.join("home"))
+            .env("XDG_CONFIG_HOME", self.dir.join("home").join(".config"))
+            .env("XDG_CACHE_HOME", self.dir.join("home").join(".cache"))
+            .env("GIT_CONFIG_NOSYSTEM", "1");
+        command
+    }
+}
+
+impl Drop for TestRepo {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+pub fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+pub fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+/// Content of the message with `role` in a recorded request.
+pub fn message<'a>(request: &'a Value, role: &str) -> &'a str {
+    request["messages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|m| m["role"] == role)
+        .and_then(|m| m["content"].as_str())
+        .unwrap_or_default()
+}
@@ -0,0 +1,977 @@
+mod common;
+
+use common::{chunk, error_body, message, stderr, stdout, MockServer, Reply, TestRepo};
+use serde_json::json;
+
+fn repo_with_history() -> TestRepo {
+    let repo = TestRepo::new();
+    repo.commit("README.md", "Initial commit");
+    repo.tag("v0.1.0");
+    repo.commit("src/lib.rs", "feat(api): add export endpoint");
+    repo.commit("src/lib.rs", "fix: handle empty input");
+    repo
+}
+
+#[test]
+fn streams_changelog_to_stdout() {
+    let repo = repo_with_history();
+    let server = MockServer::start(vec![Reply::deltas(&[
+        "## Features\n",
+        "- Export ",
+        "endpoint\n",
+    ])]);
+
+    let output = repo.run(&server, &["v0.1.0..HEAD"]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "## Features\n- Export endpoint\n");
+    assert!(stderr(&output).contains("This used"));
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0]["stream"], true);
+    let user = message(&requests[0], "user");
+    assert!(user.contains("feat(api): add export endpoint"));
+    assert!(user.contains("fix: handle empty input"));
+    assert!(!user.contains("Initial commit"));
+    assert!(!user.contains("Author:"));
+}
+
+#[test]
+fn inserts_release_into_changelog_file() {
+    let repo = repo_with_history();
+    repo.write(
+        "CHANGELOG.md",
+        "# Changelog\n\nAll notable changes.\n\n## v0.1.0\n\n- First release\n",
+    );
+    let server = MockServer::start(vec![Reply::deltas(&["# Features\n\n- Export endpoint\n"])]);
+
+    let output = repo.run(&server, &["v0.1.0..v0.2.0", "-o", "CHANGELOG.md"]);
+    assert!(!output.status.success(), "unknown revision must fail");
+
+    repo.tag("v0.2.0");
+    let output = repo.run(&server, &["v0.1.0..v0.2.0", "-o", "CHANGELOG.md"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        repo.read("CHANGELOG.md"),
+        "# Changelog\n\nAll notable changes.\n\n## v0.2.0\n\n### Features\n\n- Export endpoint\n\n## v0.1.0\n\n- First release\n"
+    );
+}
+
+#[test]
+fn creates_missing_changelog_file() {
+    let repo = repo_with_history();
+    let server = MockServer::start(vec![Reply::deltas(&["- Export endpoint"])]);
+
+    let output = repo.run(&server, &["--latest", "-o", "CHANGELOG.md"]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        repo.read("CHANGELOG.md"),
+        "# Changelog\n\n## Unreleased\n\n- Export endpoint\n"
+    );
+    assert!(message(&server.requests()[0], "user").contains("add export endpoint"));
+}
+
+#[test]
+fn reports_why_the_latest_tag_is_missing() {
+    let repo = TestRepo::new();
+    repo.commit("README.md", "Initial commit");
+    let server = MockServer::start(Vec::new());
+
+    let output = repo.run(&server, &["--latest"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("No tag found to start the range from"));
+
+    let output = repo.run_with_env(&server, &["--latest"], &[("GIT_DIR", "missing")]);
+    assert!(!output.status.success());
+    assert!(
+        stderr(&output).contains("not a git repository"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn replaces_unreleased_section() {
+    let repo = repo_with_history();
+    repo.write(
+        "CHANGELOG.md",
+        "# Changelog\n\n## Unreleased\n\n- Old draft\n\n## v0.1.0\n\n- First release\n",
+    );
+    let server = MockServer::start(vec![
+        Reply::deltas(&["- Export endpoint"]),
+        Reply::deltas(&["- Export endpoint\n- Empty input handling"]),
+    ]);
+
+    for _ in 0..2 {
+        let output = repo.run(&server, &["--latest", "--no-cache", "-o", "CHANGELOG.md"]);
+        assert!(output.status.success(), "{}", stderr(&output));
+    }
+
+    assert_eq!(
+        repo.read("CHANGELOG.md"),
+        "# Changelog\n\n## Unreleased\n\n- Export endpoint\n- Empty input handling\n\n## v0.1.0\n\n- First release\n"
+    );
+}
+
+#[test]
+fn reports_api_errors() {
+    let repo = repo_with_history();
+    let server = MockServer::start(vec![Reply::error(
+        401,
+        "Incorrect API key provided",
+        "invalid_api_key",
+    )]);
+
+    let output = repo.run(&server, &[]);
+
+    assert!(!output.status.success());
+    assert!(
+        stderr(&output).contains("Incorrect API key provided (invalid_api_key)"),
+        "{}",
+        stderr(&output)
+    );
+    assert_eq!(server.requests().len(), 1, "client errors are not retried");
+}
+
+#[test]
+fn retries_after_rate_limit() {
+    let repo = repo_with_history();
+    let server = MockServer::start(vec![
+        Reply::Json {
+            status: 429,
+            headers: vec![(String::from("Retry-After"), String::from("0"))],
+            body: error_body("Rate limit reached", "rate_limit_exceeded"),
+        },
+        Reply::deltas(&["- Export endpoint\n"]),
+    ]);
+
+    let output = repo.run(&server, &[]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("Rate limit reached"));
+    assert_eq!(stdout(&output), "- Export endpoint\n");
+    assert_eq!(server.requests().len(), 2);
+
+    let server = MockServer::start(vec![Reply::Json {
+        status: 429,
+        headers: vec![(String::from("Retry-After"), String::from("86400"))],
+        body: error_body("Rate limit reached", "rate_limit_exceeded"),
+    }]);
+    let output = repo.run(&server, &["--no-cache"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("not waiting longer than 300s"));
+    assert!(stderr(&output).contains("Rate limit reached"));
+    assert_eq!(server.requests().len(), 1);
+}
+
+#[test]
+fn retries_stream_failing_before_the_first_chunk() {
+    let repo = repo_with_history();
+    let server = MockServer::start(vec![
+        Reply::Stream(vec![error_body("The server had an error", "server_error")]),
+        Reply::deltas(&["- Export endpoint\n"]),
+    ]);
+
+    let output = repo.run(&server, &[]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("The server had an error"));
+    assert_eq!(stdout(&output), "- Export endpoint\n");
+    assert_eq!(server.requests().len(), 2);
+}
+
+#[test]
+fn skips_malformed_chunks() {
+    let repo = repo_with_history();
+    let server = MockServer::start(vec![Reply::Stream(vec![
+        chunk("- Export "),
+        String::from("{not json"),
+        chunk("endpoint\n"),
+        String::from("[DONE]"),
+    ])]);
+
+    let output = repo.run(&server, &[]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "- Export endpoint\n");
+}
+
+#[test]
+fn fails_on_error_inside_stream() {
+    let repo = repo_with_history();
+    let server = MockServer::start(vec![Reply::Stream(vec![
+        chunk("- Export "),
+        error_body("The server had an error", "server_error"),
+    ])]);
+
+    let output = repo.run(&server, &[]);
+
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("The server had an error"));
+}
+
+#[test]
+fn groups_conventional_commits() {
+    let repo = repo_with_history();
+    let server = MockServer::start(vec![Reply::deltas(&["done"])]);
+
+    let output = repo.run(&server, &["--conventional", "v0.1.0..HEAD"]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    let user = message(&server.requests()[0], "user").to_string();
+    let features = user.find("## Features").expect("features section");
+    let fixes = user.find("## Fixes").expect("fixes section");
+    assert!(features < fixes);
+    assert!(user[features..fixes].contains("(api) add export endpoint"));
+    assert!(user[fixes..].contains("handle empty input"));
+}
+
+#[test]
+fn overrides_config_from_the_command_line() {
+    let repo = repo_with_history();
+    repo.write(
+        ".aichangelog.toml",
+        "conventional = true\noutput = \"CHANGELOG.md\"\n",
+    );
+    let server = MockServer::start(vec![Reply::deltas(&["- Export endpoint"])]);
+
+    let output = repo.run_in(&server, "src", &["--no-conventional", "v0.1.0..HEAD"]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    let user = message(&server.requests()[0], "user").to_string();
+    assert!(!user.contains("## Features"), "{user}");
+    assert_eq!(
+        repo.read("CHANGELOG.md"),
+        "# Changelog\n\n## Unreleased\n\n- Export endpoint\n"
+    );
+}
+
+#[test]
+fn resolves_global_output_against_the_repository() {
+    let repo = repo_with_history();
+    repo.write_global_config("output = \"CHANGELOG.md\"\n");
+    let server = MockServer::start(vec![Reply::deltas(&["- Export endpoint"])]);
+
+    let output = repo.run_in(&server, "src", &["v0.1.0..HEAD"]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        repo.read("CHANGELOG.md"),
+        "# Changelog\n\n## Unreleased\n\n- Export endpoint\n"
+    );
+}
+
+#[test]
+fn generates_one_section_per_tag() {
+    let repo = repo_with_history();
+    repo.tag("v0.2.0");
+    let server = MockServer::start(vec![
+        Reply::deltas(&["- Initial release"]),
+        Reply::deltas(&["- Export endpoint"]),
+    ]);
+
+    let output = repo.run(&server, &["--between-tags", "-o", "CHANGELOG.md"]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        repo.read("CHANGELOG.md"),
+        "# Changelog\n\n## v0.2.0\n\n- Export endpoint\n\n## v0.1.0\n\n- Initial release\n"
+    );
+    let requests = server.requests();
+    assert!(message(&requests[0], "user").contains("Initial commit"));
+    assert!(!message(&requests[1], "user").contains("Initial commit"));
+}
+
+#[test]
+fn summarizes_logs_exceeding_the_context() {
+    let repo = TestRepo::new();
+    for i in 0..40 {
+        repo.commit(
+            "notes.txt",
+            &format!("Change number {i} with a fairly long description of what it does"),
+        );
+    }
+    let server = MockServer::with_handler(|req| {
+        if req["stream"] == true {
+            Reply::deltas(&["- Everything"])
+        } else {
+            Reply::completion("- Part")
+        }
+    });
+
+    let output = repo.run(&server, &["--context-size", "1600", "-m", "local-model"]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "- Everything\n");
+    let requests = server.requests();
+    let (last, parts) = requests.split_last().unwrap();
+    assert!(
+        parts.len() >= 2,
+        "expected several chunks, got {}",
+        parts.len()
+    );
+    let max_tokens = parts[0]["max_tokens"].as_u64().unwrap();
+    assert!(max_tokens < 1024, "summaries may use {max_tokens} tokens");
+    assert!(parts.iter().all(|req| req["max_tokens"] == max_tokens));
+    assert!(message(&parts[0], "user").contains("Change number 39"));
+    assert!(message(&parts[parts.len() - 1], "user").contains("Change number 0 "));
+    assert_eq!(last["model"], "local-model");
+    assert_eq!(
+        message(last, "user"),
+        vec!["- Part"; parts.len()].join("\n\n")
+    );
+}
+
+#[test]
+fn stops_when_summaries_do_not_shrink_the_log() {
+    let repo = TestRepo::new();
+    for i in 0..40 {
+        repo.commit(
+            "notes.txt",
+            &format!("Change number {i} with a fairly long description of what it does"),
+        );
+    }
+    // A server that ignores max_tokens and answers every part with ~900 tokens.
+    let long = "- A summary that is much longer than the part it summarizes\n".repeat(75);
+    let server = MockServer::with_handler(move |_| Reply::completion(&long));
+
+    let output = repo.run(&server, &["--context-size", "1600", "-m", "local-model"]);
+
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("Summarizing the git log made it longer"));
+    assert!(server.requests().len() < 10, "{}", server.requests().len());
+}
+
+#[test]
+fn filters_commits_before_prompting() {
+    let repo = repo_with_history();
+    repo.write("Cargo.lock", "serde 1.1\n");
+    repo.git(&["add", "Cargo.lock"]);
+    repo.git(&[
+        "commit",
+        "-q",
+        "-m",
+        "Bump serde from 1.0 to 1.1",
+        "--author",
+        "dependabot[bot] <support@github.com>",
+    ]);
+    repo.commit("src/lib.rs", "chore(deps): update tokio");
+    repo.commit("src/lib.rs", "Tweak wording [skip changelog]");
+    repo.commit("docs/guide.md", "docs: explain the export endpoint");
+    repo.git(&["checkout", "-q", "-b", "topic"]);
+    repo.commit("src/lib.rs", "feat: add import endpoint");
+    repo.git(&["checkout", "-q", "main"]);
+    repo.git(&[
+        "merge",
+        "-q",
+        "--no-ff",
+        "topic",
+        "-m",
+        "Merge branch 'topic'",
+    ]);
+    repo.write(
+        ".aichangelog.toml",
+        "[filters]\nexclude_authors = [\"^dependabot\"]\n",
+    );
+    let server = MockServer::start(vec![Reply::deltas(&["done"])]);
+
+    let output = repo.run(
+        &server,
+        &[
+            "--no-merges",
+            "--exclude-message",
+            r"^chore\(deps\)",
+            "--exclude-message",
+            r"\[skip changelog\]",
+            "v0.1.0..HEAD",
+            "--",
+            "src",
+            "Cargo.lock",
+        ],
+    );
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    let user = message(&server.requests()[0], "user").to_string();
+    assert!(user.contains("add export endpoint"));
+    assert!(user.contains("add import endpoint"));
+    for dropped in [
+        "Bump serde",
+        "update tokio",
+        "skip changelog",
+        "explain the export endpoint",
+        "Merge branch",
+    ] {
+        assert!(!user.contains(dropped), "{dropped} in {user}");
+    }
+}
+
+#[test]
+fn generates_one_changelog_per_package() {
+    let repo = TestRepo::new();
+    repo.write("Cargo.toml", "[workspace]\nmembers = [\"crates/*\"]\n");
+    repo.commit("crates/foo/Cargo.toml", "[package]\nname = \"foo\"");
+    repo.commit("crates/bar/Cargo.toml", "[package]\nname = \"bar\"");
+    repo.commit("web/index.html", "Add landing page");
+    repo.tag("foo-v0.1.0");
+    repo.commit("crates/foo/src/lib.rs", "Add foo parser");
+    repo.commit("crates/bar/src/lib.rs", "Add bar renderer");
+    repo.write(
+        ".aichangelog.toml",
+        "[[packages]]\npath = \"web\"\nname = \"site\"\n",
+    );
+    let server = MockServer::with_handler(|req| {
+        let user = message(req, "user");
+        Reply::deltas(&[if user.contains("foo parser") {
+            "- Parser"
+        } else if user.contains("bar renderer") {
+            "- Renderer"
+        } else {
+            "- Landing page"
+        }])
+    });
+
+    let output = repo.run(&server, &["--workspace", "--latest", "-o", "CHANGELOG.md"]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        repo.read("crates/foo/CHANGELOG.md"),
+        "# Changelog\n\n## Unreleased\n\n- Parser\n"
+    );
+    assert_eq!(
+        repo.read("crates/bar/CHANGELOG.md"),
+        "# Changelog\n\n## Unreleased\n\n- Renderer\n"
+    );
+    assert_eq!(
+        repo.read("web/CHANGELOG.md"),
+        "# Changelog\n\n## Unreleased\n\n- Landing page\n"
+    );
+    let requests = server.requests();
+    assert_eq!(requests.len(), 3);
+    let foo = requests
+        .iter()
+        .map(|req| message(req, "user"))
+        .find(|user| user.contains("foo parser"))
+        .unwrap();
+    assert!(!foo.contains("bar renderer"));
+    assert!(
+        !foo.contains("name = \"foo\""),
+        "released before foo-v0.1.0"
+    );
+
+    let output = repo.run(&server, &["--package", "foo", "--latest"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "# foo\n\n- Parser\n");
+    assert_eq!(server.requests().len(), 3, "served from the cache");
+}
+
+#[test]
+fn rejects_arguments_that_would_mix_packages() {
+    let repo = TestRepo::new();
+    repo.write("Cargo.toml", "[workspace]\nmembers = [\"crates/*\"]\n");
+    repo.commit("crates/foo/Cargo.toml", "[package]\nname = \"foo\"");
+    let server = MockServer::start(Vec::new());
+    let absolute = repo.path().join("CHANGELOG.md");
+
+    let output = repo.run(&server, &["--package", "foo", "--", "src"]);
+    assert!(!output.status.success());
+    assert!(
+        stderr(&output).contains("cannot be used with"),
+        "{}",
+        stderr(&output)
+    );
+
+    let output = repo.run(&server, &["--workspace", "-o", absolute.to_str().unwrap()]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("must be relative in workspace mode"));
+
+    repo.write(".aichangelog.toml", "workspace = true\n");
+    let output = repo.run(&server, &["--", "src"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("can't be combined with workspace mode"));
+    assert!(server.requests().is_empty());
+}
+
+#[test]
+fn enriches_commits_with_referenced_issues() {
+    let repo = repo_with_history();
+    repo.commit("src/lib.rs", "Fix crash on empty input (#12)");
+    repo.commit("src/lib.rs", "Merge pull request #34 from jane/import");
+    repo.commit("src/lib.rs", "Mention C#7 in the docs");
+    repo.commit("src/lib.rs", "Speed up parsing (#56)");
+    repo.git(&["remote", "add", "origin", "git@github.com:acme/widgets.git"]);
+    repo.write(
+        ".aichangelog.toml",
+        "[[sections]]\ntitle = \"Bug Fixes\"\ntypes = [\"fix\"]\nlabels = [\"bug\"]\n\n[[sections]]\ntitle = \"Features\"\nlabels = [\"enhancement\"]\n",
+    );
+    let forge = MockServer::with_handler(|req| {
+        let path = req["path"].as_str().unwrap_or_default();
+        if path.ends_with("/repos/acme/widgets/issues/12") {
+            Reply::json(json!({
+                "title": "App crashes when the input is empty",
+                "body": "Steps to reproduce:\n\n1. Clear the input",
+                "labels": [{ "name": "bug" }],
+            }))
+        } else if path.ends_with("/repos/acme/widgets/issues/34") {
+            Reply::json(json!({
+                "title": "Import endpoint",
+                "body": "Adds `POST /import` for CSV files.",
+                "labels": [{ "name": "enhancement" }],
+                "pull_request": { "url": "https://example.com" },
+            }))
+        } else if path.ends_with("/repos/acme/widgets/issues/56") {
+            Reply::Json {
+                status: 403,
+                headers: Vec::new(),
+                body: String::from(r#"{"message":"API rate limit exceeded"}"#),
+            }
+        } else {
+            Reply::Json {
+                status: 404,
+                headers: Vec::new(),
+                body: String::from(r#"{"message":"Not Found"}"#),
+            }
+        }
+    });
+    let server = MockServer::start(vec![Reply::deltas(&["done"])]);
+
+    let output = repo.run(
+        &server,
+        &[
+            "--enrich",
+            "--forge-url",
+            forge.url(),
+            "--conventional",
+            "v0.1.0..HEAD",
+        ],
+    );
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stderr(&output).contains("API rate limit exceeded"),
+        "{}",
+        stderr(&output)
+    );
+    let mut paths: Vec<String> = forge
+        .requests()
+        .iter()
+        .map(|req| req["path"].as_str().unwrap().to_string())
+        .collect();
+    paths.sort();
+    assert_eq!(
+        paths,
+        [
+            "/v1/repos/acme/widgets/issues/12",
+            "/v1/repos/acme/widgets/issues/34",
+            "/v1/repos/acme/widgets/issues/56"
+        ]
+    );
+
+    let user = message(&server.requests()[0], "user").to_string();
+    let fixes = user.find("## Bug Fixes").expect("bug fixes section");
+    let features = user.find("## Features").expect("features section");
+    assert!(user[fixes..features].contains("handle empty input"));
+    assert!(user[fixes..features].contains(
+        "Fix crash on empty input (#12)\n  issue #12: App crashes when the input is empty [bug]\n    Steps to reproduce:"
+    ));
+    assert!(user[features..].contains("pull request #34: Import endpoint [enhancement]"));
+    assert!(user[features..].contains("Adds `POST /import` for CSV files."));
+    assert!(user.contains("Speed up parsing (#56)"));
+}
+
+#[test]
+fn publishes_release_on_the_forge() {
+    let repo = repo_with_history();
+    repo.tag("v0.2.0");
+    repo.git(&[
+        "remote",
+        "add",
+        "origin",
+        "https://github.com/acme/widgets.git",
+    ]);
+    let forge = MockServer::with_handler(|req| {
+        if req["method"] == "GET" {
+            Reply::Json {
+                status: 404,
+                headers: Vec::new(),
+                body: String::from(r#"{"message":"Not Found"}"#),
+            }
+        } else {
+            Reply::json(
+                json!({ "id": 1, "html_url": "https://github.com/acme/widgets/releases/v0.2.0" }),
+            )
+        }
+    });
+    let server = MockServer::start(vec![Reply::deltas(&["- Export endpoint\n"])]);
+
+    let output = repo.run_with_env(
+        &server,
+        &[
+            "--forge-url",
+            forge.url(),
+            "publish",
+            "v0.2.0",
+            "--name",
+            "Widgets 0.2.0",
+        ],
+        &[("GITHUB_TOKEN", "ghp-test")],
+    );
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output)
+        .contains("Release published at https://github.com/acme/widgets/releases/v0.2.0"));
+    assert!(!message(&server.requests()[0], "user").contains("Initial commit"));
+    assert_eq!(
+        forge.routes(),
+        [
+            "GET /v1/repos/acme/widgets/releases/tags/v0.2.0",
+            "POST /v1/repos/acme/widgets/releases"
+        ]
+    );
+    let body = &forge.requests()[1];
+    assert_eq!(body["tag_name"], "v0.2.0");
+    assert_eq!(body["name"], "Widgets 0.2.0");
+    assert_eq!(body["body"], "- Export endpoint");
+}
+
+#[test]
+fn prints_release_request_on_dry_run() {
+    let repo = repo_with_history();
+    repo.tag("v0.2.0");
+    repo.git(&[
+        "remote",
+        "add",
+        "origin",
+        "git@gitlab.com:acme/tools/widgets.git",
+    ]);
+    let forge = MockServer::start(vec![Reply::json(json!({ "tag_name": "v0.2.0" }))]);
+    let server = MockServer::start(vec![Reply::deltas(&["- Export endpoint\n"])]);
+
+    let output = repo.run(
+        &server,
+        &["--forge-url", forge.url(), "publish", "v0.2.0", "--dry-run"],
+    );
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    let url = format!(
+        "{}/projects/acme%2Ftools%2Fwidgets/releases/v0.2.0",
+        forge.url()
+    );
+    assert!(
+        stdout(&output).contains(&format!(
+            "PUT {url}\n{{\n  \"description\": \"- Export endpoint\""
+        )),
+        "{}",
+        stdout(&output)
+    );
+    assert_eq!(forge.routes().len(), 1, "only the lookup is sent");
+}
+
+#[test]
+fn prints_next_version() {
+    let repo = repo_with_history();
+    let server = MockServer::start(vec![Reply::completion("Major")]);
+
+    let output = repo.run(&server, &["--next-version"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "v0.2.0\n");
+    assert!(server.requests().is_empty());
+
+    let output = repo.run(&server, &["--next-version", "--check-bump"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "v0.2.0\n");
+    assert!(stderr(&output)
+        .contains("The model suggests a major release, Conventional Commits a minor one"));
+    assert_eq!(server.requests()[0]["stream"], false);
+
+    repo.commit("src/lib.rs", "refactor!: drop the v1 API");
+    let output = repo.run(&server, &["--next-version"]);
+    assert_eq!(stdout(&output), "v1.0.0\n");
+
+    repo.tag("v1.0.0");
+    let output = repo.run(&server, &["--next-version"]);
+    assert!(!output.status.success());
+    assert_eq!(stdout(&output), "");
+    assert!(stderr(&output).contains("No commits since v1.0.0"));
+}
+
+#[test]
+fn heads_unreleased_changes_with_next_version() {
+    let repo = repo_with_history();
+    let server = MockServer::start(vec![Reply::deltas(&["- Export endpoint"])]);
+
+    let output = repo.run(&server, &["--latest", "--bump", "-o", "CHANGELOG.md"]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("Next version: v0.2.0 (minor)"));
+    assert_eq!(
+        repo.read("CHANGELOG.md"),
+        "# Changelog\n\n## v0.2.0\n\n- Export endpoint\n"
+    );
+}
+
+#[test]
+fn reuses_cached_changelog() {
+    let repo = repo_with_history();
+    let server = MockServer::with_handler(|_| Reply::deltas(&["- Export endpoint\n"]));
+
+    let first = repo.run(&server, &["--latest"]);
+    let second = repo.run(&server, &["--latest"]);
+
+    assert!(second.status.success(), "{}", stderr(&second));
+    assert_eq!(stdout(&first), stdout(&second));
+    assert!(stderr(&second).contains("Loaded from the cache"));
+    assert_eq!(server.requests().len(), 1);
+
+    repo.run(&server, &["--latest", "--temp", "0.5"]);
+    repo.run(&server, &["--latest", "--context-size", "1200"]);
+    repo.run(&server, &["--latest", "--no-cache"]);
+    assert_eq!(server.requests().len(), 4);
+
+    let other = MockServer::with_handler(|_| Reply::deltas(&["- Export endpoint\n"]));
+    repo.run(&other, &["--latest"]);
+    assert_eq!(other.requests().len(), 1, "same model on another server");
+
+    let output = repo.run(&server, &["cache", "clear"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).starts_with("Removed 4 cached changelogs"));
+    repo.run(&server, &["--latest"]);
+    assert_eq!(server.requests().len(), 5);
+}
+
+#[test]
+fn picks_one_of_several_candidates() {
+    let repo = repo_with_history();
+    let server = MockServer::with_handler(|_| {
+        Reply::completions(&["- Export endpoint\n", "- New export endpoint\n"])
+    });
+
+    let output = repo.run_with_input(&server, &["--latest", "--candidates", "2"], "3\n2\n");
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "- New export endpoint\n");
+    assert!(stderr(&output).contains("Candidate 1"));
+    assert!(stderr(&output).contains("- Export endpoint"));
+    let requests = server.requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0]["n"], 2);
+    assert_eq!(requests[0]["stream"], false);
+
+    let output = repo.run_with_input(&server, &["--latest", "--candidates", "2"], "");
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("No candidate picked"));
+}
+
+#[test]
+fn merges_candidates() {
+    let repo = repo_with_history();
+    let server = MockServer::start(vec![
+        Reply::completions(&["- Export endpoint\n", "- Export as CSV\n"]),
+        Reply::deltas(&["- Export endpoint with CSV support\n"]),
+    ]);
+
+    let output = repo.run_with_input(&server, &["--latest", "--candidates", "2"], "m\n");
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "- Export endpoint with CSV support\n");
+    let requests = server.requests();
+    assert_eq!(requests.len(), 2);
+    let merge = message(&requests[1], "user");
+    assert!(merge.contains("Changelog 1:\n\n- Export endpoint"));
+    assert!(merge.contains("Changelog 2:\n\n- Export as CSV"));
+}
+
+/// Structured notes citing the export commit of [`repo_with_history`].
+fn notes(repo: &TestRepo) -> String {
+    let hash = repo.git(&["rev-parse", "--short=7", "HEAD~"]);
+    json!({
+        "sections": [{
+            "title": "Added",
+            "entries": [{ "summary": "Export <endpoint>", "commits": [hash.trim()], "breaking": false }],
+        }],
+    })
+    .to_string()
+}
+
+#[test]
+fn renders_structured_formats() {
+    let repo = repo_with_history();
+    let notes = notes(&repo);
+    let hash = repo.git(&["rev-parse", "--short=7", "HEAD~"]);
+    let hash = hash.trim();
+    let server = MockServer::with_handler(move |_| Reply::deltas(&["```json\n", &notes, "\n```"]));
+
+    let output = repo.run(
+        &server,
+        &[
+            "--latest",
+            "--format",
+            "keep-a-changelog",
+            "-o",
+            "CHANGELOG.md",
+        ],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        stdout(&output),
+        format!("### Added\n\n- Export <endpoint> ({hash})\n")
+    );
+    assert!(repo.read("CHANGELOG.md").contains(&format!(
+        "## [Unreleased]\n\n### Added\n\n- Export <endpoint> ({hash})\n"
+    )));
+    let system = message(&server.requests()[0], "system").to_string();
+    assert!(system.contains("JSON Schema"));
+    assert!(system.contains("Added, Changed, Deprecated, Removed, Fixed, Security"));
+
+    let output = repo.run(&server, &["--latest", "--format", "json"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let document: serde_json::Value = serde_json::from_str(&stdout(&output)).unwrap();
+    assert_eq!(document["version"], "Unreleased");
+    assert_eq!(document["sections"][0]["entries"][0]["commits"][0], hash);
+
+    let output = repo.run(&server, &["--latest", "--format", "html"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).starts_with("<!DOCTYPE html>"));
+    assert!(stdout(&output).contains(&format!(
+        "<li>Export &lt;endpoint&gt; <code>{hash}</code></li>"
+    )));
+}
+
+#[test]
+fn rejects_invalid_structured_reply() {
+    let repo = repo_with_history();
+    let server = MockServer::start(vec![
+        Reply::deltas(&["- Export endpoint\n"]),
+        Reply::deltas(&[&notes(&repo).replace("Added", "Features")]),
+        Reply::deltas(&[&notes(&repo).replace(r#""breaking":false,"#, "")]),
+    ]);
+
+    let output = repo.run(&server, &["--latest", "--format", "text", "--repairs", "0"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("The reply is not valid changelog JSON"));
+
+    let output = repo.run(
+        &server,
+        &["--latest", "--format", "keep-a-changelog", "--repairs", "0"],
+    );
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("Section \"Features\" is not one of Added"));
+
+    let output = repo.run(&server, &["--latest", "--format", "json", "--repairs", "0"]);
+    assert!(!output.status.success());
+    assert!(
+        stderr(&output).contains("missing field `breaking`"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn repairs_invalid_structured_reply() {
+    let repo = repo_with_history();
+    let invalid = notes(&repo).replace(r#""commits":[""#, r#""commits":["fffffff",""#);
+    let server = MockServer::start(vec![
+        Reply::deltas(&[&invalid]),
+        Reply::deltas(&[&notes(&repo)]),
+    ]);
+
+    let output = repo.run(&server, &["--latest", "--format", "text"]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).starts_with("Added\n-----\n\n* Export <endpoint>"));
+    assert!(stderr(&output).contains("needed 2 attempts"));
+    let requests = server.requests();
+    assert_eq!(requests.len(), 2);
+    let messages = requests[1]["messages"].as_array().unwrap();
+    assert_eq!(messages.len(), 4);
+    assert_eq!(messages[2]["role"], "assistant");
+    assert_eq!(messages[2]["content"], invalid.as_str());
+    let correction = messages[3]["content"].as_str().unwrap();
+    assert!(
+        correction.contains(r#"cites "fffffff", which is not the short hash"#),
+        "{correction}"
+    );
+}
+
+#[test]
+fn refines_changelog_on_request() {
+    let repo = repo_with_history();
+    let server = MockServer::start(vec![
+        Reply::deltas(&["- Export endpoint\n- Empty input handling\n"]),
+        Reply::deltas(&["- Export endpoint\n"]),
+    ]);
+
+    let output = repo.run_with_input(
+        &server,
+        &["--latest", "--refine", "-o", "CHANGELOG.md"],
+        "drop the fixes\n\n",
+    );
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("Refine the changelog"));
+    assert!(stderr(&output).contains("- Empty input handling\n"));
+    assert_eq!(stdout(&output), "- Export endpoint\n");
+    assert!(repo
+        .read("CHANGELOG.md")
+        .contains("## Unreleased\n\n- Export endpoint\n"));
+    assert!(!repo.read("CHANGELOG.md").contains("Empty input"));
+    let requests = server.requests();
+    assert_eq!(requests.len(), 2);
+    let messages = requests[1]["messages"].as_array().unwrap();
+    assert_eq!(messages.len(), 4);
+    assert_eq!(messages[2]["role"], "assistant");
+    assert_eq!(
+        messages[2]["content"],
+        "- Export endpoint\n- Empty input handling\n"
+    );
+    assert_eq!(messages[3]["content"], "drop the fixes");
+}
+
+#[test]
+fn writes_edited_changelog() {
+    let repo = repo_with_history();
+    let server = MockServer::with_handler(|_| Reply::deltas(&["- Export endpoint\n"]));
+
+    let output = repo.run_with_env(
+        &server,
+        &["--latest", "--edit", "-o", "CHANGELOG.md"],
+        &[("VISUAL", "sed -i s/Export/Exported/")],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(repo.read("CHANGELOG.md").contains("- Exported endpoint\n"));
+    assert_eq!(stdout(&output), "- Exported endpoint\n");
+
+    let output = repo.run_with_env(
+        &server,
+        &["--latest", "--edit", "-o", "NOTES.md"],
+        &[("VISUAL", "truncate -s 0")],
+    );
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("Aborting because the changelog is empty"));
+    assert!(!repo.path().join("NOTES.md").exists());
+}
+
+#[test]
+fn previews_prompt_on_dry_run() {
+    let repo = repo_with_history();
+    let server = MockServer::start(Vec::new());
+
+    let output = repo.run(
+        &server,
+        &["--latest", "--bump", "--check-bump", "--dry-run"],
+    );
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    let out = stdout(&output);
+    assert!(out.starts_with("System message ("));
+    assert!(out.contains("User message ("));
+    assert!(out.contains("add export endpoint"));
+    assert!(out.contains("% of the 4096 token context of gpt-3.5-turbo"));
+    assert!(out.contains("Worst-case cost: ~$0.00"));
+    assert!(server.requests().is_empty());
+
+    let output = repo.run(&server, &["--latest", "--model", "llama3", "--dry-run"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("Worst-case cost unknown; set [pricing.\"llama3\"]"));
+}
@@ -0,0 +1,106 @@
+mod common;
+
+use std::path::Path;
+
+use aichangelog::{
+    filter::Filter,
+    git::Commit,
+    openai::{self, Model, OpenAi},
+    Options, Release,
+};
+use common::{message, MockServer, Reply, TestRepo};
+
+fn commit(subject: &str) -> Commit {
+    Commit {
+        hash: String::from("0123456789abcdef"),
+        short_hash: String::from("0123456"),
+        author: String::from("Jane Doe"),
+        date: String::from("2023-04-01"),
+        subject: String::from(subject),
+        ..Commit::default()
+    }
+}
+
+fn options() -> Options {
+    Options {
+        project_name: Some(String::from("demo")),
+        ..Options::default()
+    }
+}
+
+#[tokio::test]
+async fn generates_changelog_without_the_cli() {
+    let server = MockServer::start(vec![Reply::deltas(&["- Export ", "endpoint"])]);
+    let provider = OpenAi::new(server.url(), None, Model::Gpt35Turbo);
+    let release = Release::new(Some(String::from("v0.1.0..v0.2.0")));
+    let commits = vec![commit("feat: add export endpoint")];
+
+    let prompt =
+        aichangelog::build_prompt(Path::new("."), &provider, &options(), &release, &commits)
+            .await
+            .unwrap();
+    let changelog = aichangelog::generate_changelog(&provider, &options(), &prompt)
+        .await
+        .unwrap();
+
+    assert_eq!(release.heading, "v0.2.0");
+    assert_eq!(changelog.text, "- Export endpoint");
+    assert_eq!(changelog.usage.prompt_tokens, prompt.tokens);
+    assert!(message(&server.requests()[0], "user")
+        .contains("0123456 2023-04-01 Jane Doe: feat: add export endpoint"));
+}
+
+#[tokio::test]
+async fn returns_typed_api_errors() {
+    let server = MockServer::start(vec![Reply::error(
+        401,
+        "Incorrect API key provided",
+        "invalid_api_key",
+    )]);
+    let provider = OpenAi::new(server.url(), None, Model::Gpt35Turbo).retries(0);
+    let release = Release::new(None);
+
+    let prompt = aichangelog::build_prompt(
+        Path::new("."),
+        &provider,
+        &options(),
+        &release,
+        &[commit("Fix typo")],
+    )
+    .await
+    .unwrap();
+    let err = aichangelog::generate_changelog(&provider, &options(), &prompt)
+        .await
+        .unwrap_err();
+
+    let err = err.downcast_ref::<openai::Error>().expect("an API error");
+    assert_eq!(err.code.as_deref(), Some("invalid_api_key"));
+}
+
+#[tokio::test]
+async fn reads_the_given_repository() {
+    let repo = TestRepo::new();
+    repo.commit("a.txt", "Initial commit");
+    repo.tag("v0.1.0");
+    repo.commit("a.txt", "Add export endpoint");
+    let server = MockServer::start(vec![]);
+    let provider = OpenAi::new(server.url(), None, Model::Gpt35Turbo);
+
+    let release = Release::since_latest_tag(&repo.path(), None).unwrap();
+    let commits = aichangelog::collect_commits(&repo.path(), &release, &Filter::default()).unwrap();
+    let options = Options {
+        prompt: Some(String::from("Changes to {{project_name}}:\n{{commits}}")),
+        ..Options::default()
+    };
+    let prompt = aichangelog::build_prompt(&repo.path(), &provider, &options, &release, &commits)
+        .await
+        .unwrap();
+
+    assert_eq!(release.range.as_deref(), Some("v0.1.0..HEAD"));
+    assert_eq!(commits.len(), 1);
+    assert_eq!(commits[0].subject, "Add export endpoint");
+    assert!(prompt
+        .messages
+        .iter()
+        .any(|m| m.content.starts_with("Changes to repo:")));
+}